@@ -12,10 +12,48 @@ stereo(func) = _,_ : func(_),func(_) : _,_;
 volumeM = *(vslider("volume", 0, -70, +4, 0.1) : ba.db2linear : si.smoo);
 volume = stereo(volumeM);
 
+// Every layer has a gain and a mute, at layers/<layer>/gain and layers/<layer>/mute
+layer_control(gain, mute) = stereo(*(gain : ba.db2linear : *(1 - mute) : si.smoo));
+memory_control = layer_control(vslider("h:layers/v:memory/gain", 0, -70, +4, 0.1), checkbox("h:layers/v:memory/mute"));
+processes_control = layer_control(vslider("h:layers/v:processes/gain", 0, -70, +4, 0.1), checkbox("h:layers/v:processes/mute"));
+cpu_control = layer_control(vslider("h:layers/v:cpu/gain", 0, -70, +4, 0.1), checkbox("h:layers/v:cpu/mute"));
+packets_control = layer_control(vslider("h:layers/v:packets/gain", 0, -70, +4, 0.1), checkbox("h:layers/v:packets/mute"));
+drones_control = layer_control(vslider("h:layers/v:drones/gain", 0, -70, +4, 0.1), checkbox("h:layers/v:drones/mute"));
+swap_control = layer_control(vslider("h:layers/v:swap/gain", 0, -70, +4, 0.1), checkbox("h:layers/v:swap/mute"));
+disk_control = layer_control(vslider("h:layers/v:disk/gain", 0, -70, +4, 0.1), checkbox("h:layers/v:disk/mute"));
+
 /*
-  Process has several inputs:
-  1: CPU usage (0 to 1)
+  Process has several inputs, named in order by `declare inputs` so the daemon can patch
+  each metric channel into the right one. Event channels are impulse streams as high as
+  each event's velocity, with their values held in <channel>_<value> until the next event.
+  0 cpu_load: CPU usage (0 to 1)
+  1 mem_load: Memory usage (0 to 1)
+  2, 3 packets_in, packets_out: Packets on physical interfaces (events)
+  4, 5 process_spawn, process_spawn_pan: Spawned processes (events) and their panning (-1 to 1)
+  6, 7 process_exit, process_exit_pan: Dropped processes (events) and their panning (-1 to 1)
+  8 swap_usage: Swap usage (0 to 1)
+  9 swap_in: Pages swapped in per second
+  10 swap_out: Pages swapped out per second
+  11 disk_reads: Disk read completions (events)
+  12 disk_writes: Disk write completions (events)
+  13 disk_read_mb: Disk read throughput (MB per second)
+  14 disk_write_mb: Disk write throughput (MB per second)
+  15 per_core_mode: 1 when the per-core voices replace the averaged status tone
+  16 - 23 core_<n>: Per-core CPU usage, one per voice from left to right (0 to 1)
+  24, 25 internal_packets_in, internal_packets_out: Packets on loopback and virtual interfaces (events)
+  26 - 29 drone_<n>: Usage of the top processes, one per drone (0 to 1)
+  30 - 33 drone_<n>_pan: Panning of the top processes, one per drone (-1 to 1)
+  34 - 36 process_exit_size, process_exit_age, process_exit_work: Size, age and CPU time of dropped processes (0 to 1)
 */
+declare inputs "cpu_load mem_load packets_in packets_out process_spawn process_spawn_pan process_exit process_exit_pan swap_usage swap_in swap_out disk_reads disk_writes disk_read_mb disk_write_mb per_core_mode core_0 core_1 core_2 core_3 core_4 core_5 core_6 core_7 internal_packets_in internal_packets_out drone_0 drone_1 drone_2 drone_3 drone_0_pan drone_1_pan drone_2_pan drone_3_pan process_exit_size process_exit_age process_exit_work";
+num_inputs = 37;
+num_cpu_voices = 8;
+num_drones = 4;
+
+// Picks the inputs at the given indices off the full input bus
+metric(i) = ba.selector(i, num_inputs);
+metrics(indices) = si.bus(num_inputs) <: par(i, ba.count(indices), metric(ba.take(i + 1, indices)));
+core_metrics = metrics((0, 1, 2, 3, 4, 5, 6, 7));
 
 positive_only(sig) = select2(sig >= 0, 0, sig);
 derivative = _ <: _, @(1)  : _ - _ : positive_only : an.abs_envelope_rect(0.2) : _;
@@ -23,9 +61,10 @@ power(sig, num) = prod(i, num, sig);
 
 
 // Status tone!
-base_freq = 110;
+base_freq = hslider("base_freq", 110, 40, 440, 1) : si.smoo;
 lo_freq(cpu) = base_freq * (1 + cpu);
 hi_freq(cpu) = base_freq * (1 + 3 * cpu);
+status_envelope(cpu) = derivative(cpu) * ma.SR * 0.2  +   cpu * 0.1;
 status_tone(
   cpu_load,
   mem_load,
@@ -38,7 +77,64 @@ status_tone(
 ) = (
         os.osc(lo_freq(cpu_load)) / 2 + 
         os.osc(hi_freq(cpu_load))
-    ) * (derivative(cpu_load) * 10 * 960  +   cpu_load * 0.1) <: _, _;
+    ) * status_envelope(cpu_load) <: _, _;
+
+
+// Harmonics on top of the status tone, getting richer as memory fills up:
+// the nth overtone comes in with mem_load ^ n. They swell with the status tone's envelope,
+// but never drop below a floor set by memory alone, so a quiet CPU doesn't hide them.
+num_memory_harmonics = 6;
+memory_floor(mem_load) = power(mem_load, 2) * 0.5;
+harmonic_series(freq, mem_load) = sum(i, num_memory_harmonics,
+    os.osc(freq * (i + 2)) * power(mem_load, i + 1) / (i + 2));
+memory_harmonics(
+  cpu_load,
+  mem_load,
+  incoming_packet_stream,
+  outgoing_packet_stream,
+  pos_process_stream,
+  pos_process_pan,
+  neg_process_stream,
+  neg_process_pan
+) = harmonic_series(hi_freq(cpu_load), mem_load) * (status_envelope(cpu_load) + memory_floor(mem_load)) <: _, _;
+
+
+// Per-core voices!
+// Each voice sits at its own spot in the stereo field and only gets loud near full load,
+// so a single pegged core stands out as one point instead of a bump in the average.
+core_detune(i) = 1 + i * 0.015;
+core_pan(i) = 2.0 * i / (num_cpu_voices - 1) - 1;
+core_envelope(cpu_load) = power(cpu_load, 3) * 0.3;
+
+core_voice(i, cpu_load) = (
+        os.osc(lo_freq(cpu_load) * core_detune(i)) / 2 +
+        os.osc(hi_freq(cpu_load) * core_detune(i))
+    ) * core_envelope(cpu_load) : pan_by(_, core_pan(i));
+
+per_core_voices = par(i, num_cpu_voices, core_voice(i)) :> _, _;
+
+// In per-core mode each voice carries memory harmonics of its own, with an equal share
+// of the floor
+core_memory_harmonics(i, mem_load, cpu_load) =
+  harmonic_series(hi_freq(cpu_load) * core_detune(i), mem_load)
+  * (core_envelope(cpu_load) + memory_floor(mem_load) / num_cpu_voices)
+  : pan_by(_, core_pan(i));
+
+per_core_memory_harmonics = si.bus(num_inputs) <:
+    par(i, num_cpu_voices, metrics((1, 16 + i)) : core_memory_harmonics(i))
+  :> _, _;
+
+// Fades between the averaged status tone's sound and the per-core voices' one
+cpu_mode_mix(per_core_mode, status_l, status_r, voices_l, voices_r) =
+  status_l * (1 - per_core_mode) + voices_l * per_core_mode,
+  status_r * (1 - per_core_mode) + voices_r * per_core_mode;
+
+// Exactly one of the averaged status tone and the per-core voices is heard
+cpu_layer = si.bus(num_inputs) <:
+    metric(15),
+    (core_metrics : status_tone),
+    (metrics((16, 17, 18, 19, 20, 21, 22, 23)) : per_core_voices)
+  : cpu_mode_mix;
 
 
 neg_respecting_square = _ <: _ * _ * _;
@@ -57,6 +153,17 @@ packet_sounder(
   neg_process_pan
 ) = incoming_packet_stream * 0.05, outgoing_packet_stream * 0.05: _ , _ ;
 
+// Traffic that never leaves the box is muffled, as if heard through a wall
+internal_packet_sounder(
+  incoming_packet_stream,
+  outgoing_packet_stream
+) = incoming_packet_stream * 0.15, outgoing_packet_stream * 0.15 : stereo(fi.lowpass(2, 600));
+
+network_layer = si.bus(num_inputs) <:
+    (core_metrics : packet_sounder),
+    (metrics((24, 25)) : internal_packet_sounder)
+  :> _, _;
+
 
 // panning signal expected -1 to 1 inclusive
 pan_by_6db(sig, panning) = sig <: _ * (panning + 1) / 2, _ * (-panning + 1) / 2;
@@ -80,8 +187,27 @@ process_sounder(
   neg_process_stream,
   neg_process_pan
 ) = 
-  panned_process(hi_freq(cpu_load) * 2, pos_process_stream, pos_process_pan),
-  panned_process(hi_freq(cpu_load), neg_process_stream, neg_process_pan) :> _, _;
+  panned_process(hi_freq(cpu_load) * 2, pos_process_stream, pos_process_pan);
+
+// Exits are pitched by how much memory the process held (bigger is deeper, and the daemon
+// makes it louder too), and ring on for longer the longer it lived and the more CPU it used.
+exit_sounder(
+  cpu_load,
+  neg_process_stream,
+  neg_process_pan,
+  neg_process_size,
+  neg_process_age,
+  neg_process_work
+) = sy.combString(freq, decay, neg_process_stream) * 0.2 : pan_by(_, neg_process_pan)
+with {
+  freq = hi_freq(cpu_load) * (2 - 1.5 * neg_process_size);
+  decay = 0.05 + 0.5 * neg_process_age + 0.3 * neg_process_work;
+};
+
+process_layer = si.bus(num_inputs) <:
+    (core_metrics : process_sounder),
+    (metrics((0, 6, 7, 34, 35, 36)) : exit_sounder)
+  :> _, _;
 
 
 memory_pressure_aleter(
@@ -96,5 +222,69 @@ memory_pressure_aleter(
 ) = 
   os.lf_squarewavepos((2 / (1.2 - power(mem_load, 10)))) : _ * 0.5 + 1 : hi_freq(cpu_load) * _ : os.square : _ * power(mem_load, 25) * 0.05 <: _, _;
 
-process = _, _, _, _, _, _, _, _ <: process_sounder :> _ * 0.25, _ * 0.25 : volume : _,_;
+// Swap alarm!
+// swap_usage is the fraction of swap in use, swap_in / swap_out are pages per second.
+// Having swap in use is a quiet warning; pages actually moving (thrashing) sets off a
+// two-tone siren that gets louder, higher and faster the harder the box is swapping.
+thrash(swap_in, swap_out) = (swap_in + swap_out) / (swap_in + swap_out + 1000) : si.smooth(ba.tau2pole(0.3));
+
+swap_alarm(
+  swap_usage,
+  swap_in,
+  swap_out
+) = siren * level <: _, _
+with {
+  severity = thrash(swap_in, swap_out);
+  siren_rate = 1 + 7 * severity;
+  siren_freq = os.lf_squarewavepos(siren_rate) * 220 + 660 + 440 * severity;
+  siren = os.sawtooth(siren_freq) : fi.lowpass(2, 2000 + 4000 * severity);
+  level = power(swap_usage, 4) * 0.05 + power(severity, 2) * 2 : si.smoo;
+};
+
+// Disk I/O!
+// Every completed read or write is a tick, and throughput opens up a filtered bed of
+// noise so sustained transfers are heard as a rushing texture. Reads left, writes right.
+throughput_level(mb_per_sec) = mb_per_sec / (mb_per_sec + 50) : si.smooth(ba.tau2pole(0.2));
+
+disk_texture(op_stream, mb_per_sec) =
+  op_stream * 0.2 + no.noise * power(throughput_level(mb_per_sec), 2) * 0.1
+  : fi.resonlp(300 + 5000 * throughput_level(mb_per_sec), 2, 1);
+
+disk_sounder(
+  read_stream,
+  write_stream,
+  read_throughput,
+  write_throughput
+) = disk_texture(read_stream, read_throughput), disk_texture(write_stream, write_throughput);
+
+
+// Process drones!
+// The busiest processes each hold a voice that swells with their usage and sits where
+// their executable lives in the stereo field. Each slot gets its own note below the status tone.
+drone_ratios = (1, 1.25, 1.5, 1.875);
+drone_voice(i, level, pan) =
+  os.sawtooth(freq) : fi.lowpass(2, freq * (2 + 6 * level)) : _ * level * 0.15 : pan_by(_, pan)
+with {
+  freq = base_freq / 2 * ba.take(i + 1, drone_ratios);
+};
+
+drone_layer = si.bus(num_inputs) <:
+    par(i, num_drones, metrics((26 + i, 30 + i)) : drone_voice(i))
+  :> _, _;
+
+
+// The harmonics go with whichever of the status tone and the per-core voices is heard
+memory_layer = si.bus(num_inputs) <:
+    (metric(15), (core_metrics : memory_harmonics), per_core_memory_harmonics : cpu_mode_mix),
+    (core_metrics : memory_pressure_aleter)
+  :> _, _;
 
+process = si.bus(num_inputs) <:
+    (memory_layer : memory_control),
+    (process_layer : processes_control),
+    (cpu_layer : cpu_control),
+    (network_layer : packets_control),
+    (drone_layer : drones_control),
+    (metrics((8, 9, 10)) : swap_alarm : swap_control),
+    (metrics((11, 12, 13, 14)) : disk_sounder : disk_control)
+  :> _ * 0.25, _ * 0.25 : volume : _,_;
@@ -3,83 +3,131 @@
 
 use anyhow::Result;
 use hyper::service::{make_service_fn, service_fn};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{self, HeaderValue};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use std::net::SocketAddr;
-use std::str::FromStr;
-use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
-
-#[macro_use]
-extern crate lazy_static;
+use tokio::sync::{mpsc, oneshot};
+
+/// Refusal is why an offer wasn't answered
+pub enum Refusal {
+    /// Something is wrong with the offer itself
+    BadOffer(String),
+    /// There's no room for another listener right now
+    Busy(String),
+}
 
-lazy_static! {
-    static ref SDP_CHAN_TX_MUTEX: Arc<Mutex<Option<mpsc::Sender<String>>>> =
-        Arc::new(Mutex::new(None));
+/// Signal is a request from a WHEP client, answered through its reply channel
+pub enum Signal {
+    /// An SDP offer from a new listener. Reply with an id for the listener's session and
+    /// the SDP answer, or why there isn't one.
+    Offer {
+        sdp: String,
+        reply: oneshot::Sender<Result<(String, String), Refusal>>,
+    },
+    /// A listener hanging up. Reply with whether the session existed.
+    HangUp {
+        id: String,
+        reply: oneshot::Sender<bool>,
+    },
 }
 
-// HTTP Listener to get sdp
-async fn remote_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-    match (req.method(), req.uri().path()) {
-        // A HTTP handler that processes a SessionDescription given to us from the other WebRTC-rs or Pion process
-        (&Method::POST, "/sdp") => {
-            //println!("remote_handler receive from /sdp");
-            let sdp_str = match std::str::from_utf8(&hyper::body::to_bytes(req.into_body()).await?)
-            {
-                Ok(s) => s.to_owned(),
-                Err(err) => panic!("{}", err),
-            };
+const WHEP_PATH: &str = "/whep";
+// Offers are a few kilobytes, this leaves plenty of room without reading whatever we're sent
+const MAX_OFFER_BYTES: usize = 64 * 1024;
 
-            {
-                let sdp_chan_tx = SDP_CHAN_TX_MUTEX.lock().await;
-                if let Some(tx) = &*sdp_chan_tx {
-                    let _ = tx.send(sdp_str).await;
-                }
-            }
+// No CORS headers, so only the page we serve ourselves can make a browser connect. Any
+// other page could otherwise have its visitors' browsers open listeners by the dozen.
+fn whep_response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    Response::builder().status(status).body(body.into()).unwrap()
+}
 
-            let mut response = Response::new(Body::empty());
-            *response.status_mut() = StatusCode::OK;
+async fn whep_handler(
+    req: Request<Body>,
+    signals: mpsc::Sender<Signal>,
+    page: Bytes,
+) -> Result<Response<Body>, hyper::Error> {
+    let path = req.uri().path().to_owned();
+    match (req.method(), path.as_str()) {
+        (&Method::GET, "/") => {
+            let mut response = whep_response(StatusCode::OK, page);
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
             Ok(response)
         }
-        // Return the 404 Not Found for other routes.
-        _ => {
-            let mut not_found = Response::default();
-            *not_found.status_mut() = StatusCode::NOT_FOUND;
-            Ok(not_found)
+        (&Method::POST, WHEP_PATH) => {
+            let mut body = req.into_body();
+            let mut offer = Vec::new();
+            while let Some(chunk) = body.data().await {
+                offer.extend_from_slice(&chunk?);
+                if offer.len() > MAX_OFFER_BYTES {
+                    return Ok(whep_response(StatusCode::PAYLOAD_TOO_LARGE, "The offer is too big"));
+                }
+            }
+            let sdp = match String::from_utf8(offer) {
+                Ok(sdp) => sdp,
+                Err(_) => return Ok(whep_response(StatusCode::BAD_REQUEST, "The offer isn't UTF-8")),
+            };
+            let (reply, answer) = oneshot::channel();
+            if signals.send(Signal::Offer { sdp, reply }).await.is_err() {
+                return Ok(whep_response(StatusCode::SERVICE_UNAVAILABLE, "Not taking listeners"));
+            }
+            Ok(match answer.await {
+                Ok(Ok((id, sdp))) => {
+                    let mut response = whep_response(StatusCode::CREATED, sdp);
+                    let headers = response.headers_mut();
+                    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/sdp"));
+                    // Where to DELETE to hang up
+                    if let Ok(location) = HeaderValue::from_str(&format!("{}/{}", WHEP_PATH, id)) {
+                        headers.insert(header::LOCATION, location);
+                    }
+                    response
+                }
+                Ok(Err(Refusal::BadOffer(err))) => whep_response(StatusCode::BAD_REQUEST, err),
+                Ok(Err(Refusal::Busy(err))) => whep_response(StatusCode::SERVICE_UNAVAILABLE, err),
+                Err(_) => whep_response(StatusCode::SERVICE_UNAVAILABLE, "Not taking listeners"),
+            })
+        }
+        (&Method::DELETE, _) if path.starts_with(&format!("{}/", WHEP_PATH)) => {
+            let id = path[WHEP_PATH.len() + 1..].to_owned();
+            let (reply, existed) = oneshot::channel();
+            if signals.send(Signal::HangUp { id, reply }).await.is_err() {
+                return Ok(whep_response(StatusCode::SERVICE_UNAVAILABLE, Body::empty()));
+            }
+            Ok(match existed.await {
+                Ok(true) => whep_response(StatusCode::OK, Body::empty()),
+                _ => whep_response(StatusCode::NOT_FOUND, Body::empty()),
+            })
         }
+        _ => Ok(whep_response(StatusCode::NOT_FOUND, Body::empty())),
     }
 }
 
-/// http_sdp_server starts a HTTP Server that consumes SDPs
-pub async fn http_sdp_server(port: u16) -> mpsc::Receiver<String> {
-    let (sdp_chan_tx, sdp_chan_rx) = mpsc::channel::<String>(1);
-    {
-        let mut tx = SDP_CHAN_TX_MUTEX.lock().await;
-        *tx = Some(sdp_chan_tx);
-    }
-
+/// whep_server answers WebRTC offers over HTTP the way WHEP does: POST an SDP offer to
+/// /whep and the SDP answer comes back in the response, with a Location to DELETE to hang
+/// up. Every request comes out of the returned channel, to be answered through its reply.
+/// GET / serves `page`, for a listener page that does all that from the browser.
+pub fn whep_server(addr: SocketAddr, page: String) -> Result<mpsc::Receiver<Signal>> {
+    let (signals_tx, signals_rx) = mpsc::channel::<Signal>(1);
+    let page = Bytes::from(page);
+    let service = make_service_fn(move |_| {
+        let signals_tx = signals_tx.clone();
+        let page = page.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                whep_handler(req, signals_tx.clone(), page.clone())
+            }))
+        }
+    });
+    // Binding here so a bad address is an error now rather than a log line later
+    let server = Server::try_bind(&addr)?.serve(service);
     tokio::spawn(async move {
-        let addr = SocketAddr::from_str(&format!("0.0.0.0:{}", port)).unwrap();
-        let service =
-            make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(remote_handler)) });
-        let server = Server::bind(&addr).serve(service);
-        // Run this server for... forever!
         if let Err(e) = server.await {
             eprintln!("server error: {}", e);
         }
     });
-
-    sdp_chan_rx
-}
-
-/// must_read_stdin blocks until input is received from stdin
-pub fn must_read_stdin() -> Result<String> {
-    let mut line = String::new();
-
-    std::io::stdin().read_line(&mut line)?;
-    line = line.trim().to_owned();
-    println!();
-
-    Ok(line)
+    Ok(signals_rx)
 }
 
 // Allows compressing offer/answer to bypass terminal input limits.
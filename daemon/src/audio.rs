@@ -1,9 +1,13 @@
-use tokio::time::Duration;
-use sysinfo::{NetworkExt, ProcessorExt, System, SystemExt,  PidExt};
-use faust_state::DspHandle;
+use anyhow::{bail, Result};
+use faust_state::{DspHandle, StateHandle, WidgetType};
+use faust_types::{FaustDsp, Meta, ParamIndex};
 use smallvec::SmallVec;
-use rand::Rng;
-use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use crate::fanout::Fanout;
+use crate::patchbay::{Patchbay, PolledSource};
 
 
 
@@ -11,195 +15,192 @@ mod faust {
     include!(concat!(env!("OUT_DIR"), "/dsp.rs"));
 }
 
-const SMEAR_RATIO: f32 = 0.1;
-const FRAME_SIZE: usize = 960;
+// The DSP's parameters, settable from anywhere while it runs
+pub type DspState = Arc<Mutex<StateHandle>>;
 
-type AudioThreadChannel = tokio::sync::mpsc::Sender<Vec<(i16, i16)>>;
-type AudioFrame = [f32; FRAME_SIZE];
-
-
-fn mount_positive_samples_in_buffer(num: usize) -> AudioFrame {
-    let mut samples_buffer: AudioFrame = [0f32; FRAME_SIZE];
-    let mut rng = rand::thread_rng();
-    for _ in 0..num {
-        // at max, i want the packet buffer to be alternating 1s and 0s
-        let position = rng.gen::<usize>() % (FRAME_SIZE / 2);
-        samples_buffer[position * 2] += 1.;
-    };
-    samples_buffer
-}
-
-fn mount_processes_in_buffer(set: &HashSet<&sysinfo::Pid>, prev_pan: &mut f32) -> (AudioFrame, AudioFrame) {
-    let mut samples_buffer: AudioFrame = [0f32; FRAME_SIZE];
-    // 1   0   0   0   1 
-    // 0.4 0.4 0.4 0.4 -.9
-    let mut panning_buffer: AudioFrame = [*prev_pan; FRAME_SIZE];
-    let mut rng = rand::thread_rng();
-    for pid in set.into_iter() {
-        // at max, i want the packet buffer to be alternating 1s and 0s
-        let position = rng.gen::<usize>() % (FRAME_SIZE / 2);
-        samples_buffer[position * 2] += 1.;
-        let mut current = (position * 2) + 1;
-
-        // Really really bad hash function that doesn't really actually matter
-        let pan = ((pid.as_u32() * 1337  % 256) as f32) / 128.0 - 1.;
-
-
-        while (current < FRAME_SIZE) & (samples_buffer[current] != 1.) {
-            panning_buffer[current] = pan;
-            current += 1;
-            if current >= FRAME_SIZE {
-                *prev_pan = pan;
-                break;
-            }
-        }
-    };
-    (samples_buffer, panning_buffer)
-}
-
-fn get_process_set(sys: &System) -> HashSet<sysinfo::Pid> {
-    // This doesn't refresh the system.processes buffer
-    sys.processes().keys().cloned().collect()
+// There's only a DSP to control when we're making audio
+pub fn lock_dsp(dsp_state: &Option<DspState>) -> Result<MutexGuard<'_, StateHandle>> {
+    match dsp_state {
+        Some(dsp_state) => Ok(dsp_state.lock().unwrap()),
+        None => bail!("Audio is off, so there's no DSP to control"),
+    }
 }
 
+// What Opus can encode
+const OPUS_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+const OPUS_FRAME_MICROS: [u128; 6] = [2500, 5000, 10000, 20000, 40000, 60000];
 
-struct AudioGenState {
-    pub cpu_usage_smooth: f32, // range [0, 1]
-    pub mem_usage_smooth: f32, // range [0, 1]
-    pub prev_pan_spawned: f32,
-    pub prev_pan_dropped: f32,
-    pub process_set: HashSet<sysinfo::Pid>,
-    pub system: System,
+// Shared by the DSP, the renderer and every sink, so they all agree on what a frame is
+#[derive(Clone, Copy, Debug)]
+pub struct AudioConfig {
+    pub sample_rate: u32,
+    pub frame_duration: Duration,
 }
 
-impl AudioGenState {
-    pub fn new() -> AudioGenState {
-        let mut system = System::new_all();
-        system.refresh_all();
-
-        AudioGenState {
-            cpu_usage_smooth: 0.0, // range [0, 1]
-            mem_usage_smooth: 0.0, // range [0, 1]
-            prev_pan_spawned: 0.0,
-            prev_pan_dropped: 0.0,
-            process_set: get_process_set(&system),
-            system: system
+impl AudioConfig {
+    pub fn new(sample_rate: u32, frame_duration: Duration) -> Result<AudioConfig> {
+        let config = AudioConfig { sample_rate, frame_duration };
+        if config.frame_size() == 0 || !(sample_rate as u128 * frame_duration.as_micros()).is_multiple_of(1_000_000) {
+            bail!(
+                "A {} ms frame isn't a whole number of samples at {} Hz",
+                frame_duration.as_secs_f64() * 1000.,
+                sample_rate
+            );
         }
+        Ok(config)
     }
 
-    fn get_avg_cpu_usage(&mut self) -> f32 {
-        let total = self.system.processors().into_iter().map(|x| x.cpu_usage()).sum::<f32>();
-        let raw = total / self.system.processors().len() as f32;
-        raw / 100.0
+    // Samples per channel in one frame
+    pub fn frame_size(&self) -> usize {
+        (self.sample_rate as u128 * self.frame_duration.as_micros() / 1_000_000) as usize
     }
 
-    fn cpu_buf(&mut self) -> AudioFrame {
-        let system = &mut self.system;
-        system.refresh_cpu();
-        let old_cpu_usage_smooth = self.cpu_usage_smooth;
-        let avg_cpu_usage = self.get_avg_cpu_usage();
-        if avg_cpu_usage.is_normal() {
-            self.cpu_usage_smooth =
-                self.cpu_usage_smooth + (avg_cpu_usage - self.cpu_usage_smooth) * SMEAR_RATIO;
-    
-        };
-        let mut new_buf: AudioFrame = [0.0; FRAME_SIZE];
-        for i in 0..FRAME_SIZE {
-            let ratio = i as f32 / FRAME_SIZE as f32;
-            new_buf[i] = (old_cpu_usage_smooth) * (1. - ratio) + self.cpu_usage_smooth * ratio
+    pub fn check_opus(&self) -> Result<()> {
+        if !OPUS_SAMPLE_RATES.contains(&self.sample_rate) {
+            bail!("Opus can't encode {} Hz, try one of {:?}", self.sample_rate, OPUS_SAMPLE_RATES);
+        }
+        if !OPUS_FRAME_MICROS.contains(&self.frame_duration.as_micros()) {
+            bail!(
+                "Opus can't encode {} ms frames, try 2.5, 5, 10, 20, 40 or 60",
+                self.frame_duration.as_secs_f64() * 1000.
+            );
         }
-        new_buf
+        Ok(())
     }
+}
+
+const MIN_DEMAND_TIMEOUT: Duration = Duration::from_millis(100);
 
-    fn mem_buf(&mut self) -> AudioFrame {
-        let system = &mut self.system;
-        system.refresh_memory();
-        let normed_mem_usage_raw = system.used_memory() as f32 / system.total_memory() as f32;
-        if normed_mem_usage_raw.is_normal() {
-            self.mem_usage_smooth = (1. - SMEAR_RATIO) * (self.mem_usage_smooth)  + SMEAR_RATIO * normed_mem_usage_raw;
+// What decides when the next frame gets rendered
+pub enum RenderClock {
+    // Our own timer, for sinks that don't have a clock of their own
+    Timer,
+    // The sink asks for frames, a permit each, so we follow the sound card's clock instead
+    // of drifting away from it. Falls back to the timer when the card stops asking, so a
+    // stuck card doesn't stop the other sinks.
+    Demand(Arc<Semaphore>),
+}
+
+// Picks up the `declare inputs` line of the DSP, which names its inputs in order
+#[derive(Default)]
+struct InputNames(Vec<String>);
+
+impl Meta for InputNames {
+    fn declare(&mut self, key: &str, value: &str) {
+        if key == "inputs" {
+            self.0 = value.split_whitespace().map(String::from).collect();
         }
-        [self.mem_usage_smooth; FRAME_SIZE]
     }
+}
 
-    fn packet_buf(&mut self) -> (AudioFrame, AudioFrame) {
-        let system = &mut self.system;
-        system.refresh_networks();
-        let mut num_inc_packets = 0;
-        let mut num_out_packets = 0;
-        for (_, data) in system.networks() {
-            num_inc_packets += data.packets_received() as usize;
-            num_out_packets += data.packets_transmitted() as usize;
-        };
-        (
-            mount_positive_samples_in_buffer(num_inc_packets),
-            mount_positive_samples_in_buffer(num_out_packets)
-        )
-    }
+// A slider or button the DSP exposes
+pub struct DspParam {
+    pub path: String,
+    pub value: f32,
+    // Min and max, for the widgets that have them
+    pub range: Option<(f32, f32)>,
+}
 
-    // spawned, spawned_pan, dropped, dropped_pan
-    fn process_buf(&mut self) -> (AudioFrame, AudioFrame, AudioFrame, AudioFrame) {
-        self.system.refresh_processes();
-        let new_process_set = get_process_set(&self.system);
+fn param_range(widget_type: &WidgetType) -> Option<(f32, f32)> {
+    match widget_type {
+        WidgetType::VerticalSlider(input) | WidgetType::HorizontalSlider(input) | WidgetType::NumEntry(input) => {
+            Some((input.min, input.max))
+        }
+        WidgetType::Button | WidgetType::Toggle => Some((0., 1.)),
+        _ => None,
+    }
+}
 
-        let spawned = new_process_set.difference(&self.process_set).collect::<HashSet<&sysinfo::Pid>>();
-        let dropped = self.process_set.difference(&new_process_set).collect::<HashSet<&sysinfo::Pid>>();
+pub fn list_dsp_params(state: &StateHandle) -> Vec<DspParam> {
+    state
+        .params()
+        .iter()
+        .map(|(idx, node)| DspParam {
+            path: node.path(),
+            value: state.get_param(*idx).copied().unwrap_or(0.),
+            range: param_range(node.widget_type()),
+        })
+        .collect()
+}
 
-        let (pos_process_buffer, pos_pan_buffer) = mount_processes_in_buffer(&spawned, &mut self.prev_pan_spawned);
-        let (neg_process_buffer, neg_pan_buffer) = mount_processes_in_buffer(&dropped, &mut self.prev_pan_dropped);
+// What the DSP starts the parameter at
+pub fn default_dsp_param(state: &StateHandle, name: &str) -> Result<f32> {
+    let idx = find_dsp_param(state, name)?;
+    Ok(match state.params().get(&idx).map(|node| node.widget_type()) {
+        Some(WidgetType::VerticalSlider(input) | WidgetType::HorizontalSlider(input) | WidgetType::NumEntry(input)) => {
+            input.init
+        }
+        _ => 0.,
+    })
+}
 
-        // 1000000000000100000000100000001000000100001000000000100.
-        // -.8,-.8,-.8,-.3,-.3,-.3,
+pub fn get_dsp_param(state: &StateHandle, name: &str) -> Result<f32> {
+    let idx = find_dsp_param(state, name)?;
+    Ok(state.get_param(idx).copied().unwrap_or(0.))
+}
 
-        self.process_set = new_process_set;
-        (pos_process_buffer, pos_pan_buffer, neg_process_buffer, neg_pan_buffer)
+fn find_dsp_param(state: &StateHandle, name: &str) -> Result<ParamIndex> {
+    let suffix = format!("/{}", name);
+    let idx = state
+        .params()
+        .iter()
+        .find(|(_, node)| node.path() == name || node.path().ends_with(&suffix))
+        .map(|(idx, _)| *idx);
+    match idx {
+        Some(idx) => Ok(idx),
+        None => {
+            let known = state.params().values().map(|node| node.path()).collect::<Vec<String>>();
+            bail!("The DSP has no parameter called {}, it has {}", name, known.join(", "))
+        }
     }
 }
 
-async fn audio(sink: AudioThreadChannel) {
-    // DSP Init
-    let mut dsp = Box::new(DspHandle::<faust::Sonify>::new().0);
-    dsp.init(48000);
-    let num_inputs = dsp.num_inputs();
-    let num_outputs = dsp.num_outputs();
-    println!("inputs: {}", num_inputs);
-    println!("outputs: {}", num_outputs);
+// Sets DSP sliders by their names or full paths. Nothing is set unless every name is
+// known and every value in range. Takes effect on the next frame.
+pub fn set_dsp_params(state: &mut StateHandle, params: &BTreeMap<String, f32>) -> Result<()> {
+    let mut indices = Vec::new();
+    for (name, value) in params {
+        let idx = find_dsp_param(state, name)?;
+        if let Some((min, max)) = state.params().get(&idx).and_then(|node| param_range(node.widget_type())) {
+            if !(min..=max).contains(value) {
+                bail!("{} goes from {} to {}, so it can't be {}", name, min, max, value);
+            }
+        }
+        indices.push(idx);
+    }
+    for (idx, value) in indices.into_iter().zip(params.values()) {
+        state.set_param(idx, *value);
+    }
+    state.send();
+    Ok(())
+}
 
-    let mut audio_gen_state = AudioGenState::new();
-    let mut ticker = tokio::time::interval(Duration::from_millis(20));
+async fn audio(
+    mut dsp: Box<DspHandle<faust::Sonify>>,
+    mut sinks: Fanout,
+    mut patchbay: Patchbay,
+    config: AudioConfig,
+    mut clock: RenderClock,
+    done_tx: tokio::sync::mpsc::Sender<()>,
+) {
+    let frame_size = config.frame_size();
+    let mut ticker = tokio::time::interval(config.frame_duration);
+    // A sound card asks for a frame every frame_duration, so this long without means it's
+    // stuck. Some call back for several frames at a time though, hence the minimum.
+    let demand_timeout = (config.frame_duration * 2).max(MIN_DEMAND_TIMEOUT);
+    let mut card_quiet = false;
     loop {
-        // Create and populate buffers
-        let cpu_buffer: AudioFrame = audio_gen_state.cpu_buf();
-        let mem_buffer: AudioFrame = audio_gen_state.mem_buf();
-
-        let (inc_packet_buffer, out_packet_buffer) = audio_gen_state.packet_buf();
-        let (
-            pos_process_buffer,
-            pos_pan_buffer,
-            neg_process_buffer,
-            neg_pan_buffer,
-        ) = audio_gen_state.process_buf();
-
-        let inputs = SmallVec::from([
-            &cpu_buffer[..],
-            &mem_buffer[..],
-            &inc_packet_buffer[..],
-            &out_packet_buffer[..],
-            &pos_process_buffer[..],
-            &pos_pan_buffer[..],
-            &neg_process_buffer[..],
-            &neg_pan_buffer[..],
-        ]);
-
-        //print!("{:?}", pos_pan_buffer);
+        patchbay.update();
+        let inputs = patchbay.inputs();
 
-        let mut one: AudioFrame = [0.0; FRAME_SIZE];
-        let mut two: AudioFrame = [0.0; FRAME_SIZE];
+        let mut one = vec![0.0; frame_size];
+        let mut two = vec![0.0; frame_size];
         let mut outputs = SmallVec::<[&mut [f32]; 2]>::from([
             &mut one[..],
             &mut two[..]
         ]);
 
-        dsp.update_and_compute(FRAME_SIZE as i32, &inputs[..], &mut outputs[..]);
+        dsp.update_and_compute(frame_size as i32, &inputs[..], &mut outputs[..]);
         let left_out_vec = outputs[0].to_vec();
         let right_out_vec = outputs[1].to_vec();
 
@@ -213,11 +214,78 @@ async fn audio(sink: AudioThreadChannel) {
 
         let out_samples = left_out_samples.zip(right_out_samples).collect();
 
-        sink.send(out_samples).await.expect("Oh no! Sending didn't work!");
-        ticker.tick().await;
+        if !sinks.send(out_samples) {
+            // Every sink has shut down, like recordings that have been finished or failed
+            let _ = done_tx.try_send(());
+            break;
+        }
+        let device_failed = match &clock {
+            RenderClock::Timer => {
+                ticker.tick().await;
+                false
+            }
+            // While the sound card is quiet, like when PulseAudio restarts, the timer keeps
+            // the other sinks going until it asks for frames again
+            RenderClock::Demand(demand) if card_quiet => {
+                ticker.tick().await;
+                if let Ok(permit) = demand.try_acquire() {
+                    permit.forget();
+                    println!("Local audio is asking for frames again, following its clock");
+                    card_quiet = false;
+                }
+                false
+            }
+            RenderClock::Demand(demand) => match tokio::time::timeout(demand_timeout, demand.acquire()).await {
+                Ok(Ok(permit)) => {
+                    permit.forget();
+                    false
+                }
+                // Closed when the sound card's stream fails, so nothing will ask for more
+                Ok(Err(_)) => true,
+                Err(_) => {
+                    println!("Local audio stopped asking for frames, rendering on a timer until it does");
+                    card_quiet = true;
+                    ticker.reset();
+                    false
+                }
+            },
+        };
+        if device_failed {
+            println!("Local audio has stopped, rendering on a timer for the other outputs");
+            clock = RenderClock::Timer;
+            ticker.reset();
+        }
     }
 }
 
-pub fn spawn_audio_thread(sink: AudioThreadChannel){
-    tokio::spawn(audio(sink));
+pub fn spawn_audio_thread(
+    sinks: Fanout,
+    sources: Vec<PolledSource>,
+    config: AudioConfig,
+    clock: RenderClock,
+    dsp_params: &BTreeMap<String, f32>,
+    done_tx: tokio::sync::mpsc::Sender<()>,
+) -> Result<DspState> {
+    // DSP Init
+    let (dsp, mut state) = DspHandle::<faust::Sonify>::new();
+    let mut dsp = Box::new(dsp);
+    dsp.init(config.sample_rate as i32);
+    let num_inputs = dsp.num_inputs();
+    let num_outputs = dsp.num_outputs();
+    println!("inputs: {}", num_inputs);
+    println!("outputs: {}", num_outputs);
+
+    let mut input_names = InputNames::default();
+    FaustDsp::metadata(&<faust::Sonify as FaustDsp>::new(), &mut input_names);
+    assert_eq!(
+        input_names.0.len(),
+        num_inputs as usize,
+        "The DSP must name each of its inputs in `declare inputs`"
+    );
+
+    set_dsp_params(&mut state, dsp_params)?;
+
+    let patchbay = Patchbay::new(sources, &input_names.0, config);
+    tokio::spawn(audio(dsp, sinks, patchbay, config, clock, done_tx));
+    Ok(Arc::new(Mutex::new(state)))
 }
\ No newline at end of file
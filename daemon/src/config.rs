@@ -0,0 +1,614 @@
+// Settings from audimon.toml. Anything the file leaves out gets the defaults below, and
+// command line flags win over the file.
+use anyhow::{bail, Context, Result};
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Debug};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use crate::audio::AudioConfig;
+use crate::metrics::{DroneMetric, PollIntervals, SourceOptions};
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub audio: AudioSection,
+    pub sources: SourcesSection,
+    pub mapping: MappingSection,
+    // Starting values for the DSP's sliders, by name
+    pub dsp: BTreeMap<String, f32>,
+    pub sinks: SinksSection,
+    pub control: ControlSection,
+    pub osc: OscSection,
+    pub midi: MidiSection,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioSection {
+    pub sample_rate: u32,
+    pub frame_ms: f64,
+}
+
+impl Default for AudioSection {
+    fn default() -> AudioSection {
+        AudioSection {
+            sample_rate: 48000,
+            frame_ms: 20.,
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourcesSection {
+    pub per_core: bool,
+    pub drones_by: DroneMetric,
+    pub net_include: Vec<String>,
+    pub net_exclude: Vec<String>,
+    pub poll_ms: PollMs,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollMs {
+    pub cpu: u64,
+    pub memory: u64,
+    pub network: u64,
+    pub disk: u64,
+    // Scanning every process is by far the slowest thing we poll
+    pub processes: u64,
+}
+
+impl Default for PollMs {
+    fn default() -> PollMs {
+        PollMs {
+            cpu: 100,
+            memory: 250,
+            network: 100,
+            disk: 100,
+            processes: 500,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MappingSection {
+    // How far smoothed levels move towards each new reading every 20ms, in (0, 1]
+    pub smoothing: f32,
+}
+
+impl Default for MappingSection {
+    fn default() -> MappingSection {
+        MappingSection { smoothing: 0.1 }
+    }
+}
+
+// Written as "webrtc", "local", "none", "wav:<path>" or "segments:<directory>", in the file
+// and with --output
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Output {
+    Webrtc,
+    Local,
+    Wav(PathBuf),
+    // Rotating Ogg/Opus files, see [sinks.segments]
+    Segments(PathBuf),
+    // No audio at all, for when the metrics only go out as OSC or MIDI
+    None,
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(output: &str) -> Result<Output> {
+        Ok(match output {
+            "webrtc" => Output::Webrtc,
+            "local" => Output::Local,
+            "none" => Output::None,
+            _ => match output.split_once(':') {
+                Some(("wav", "")) => bail!("wav: needs a path to write to, like wav:incident.wav"),
+                Some(("wav", path)) => Output::Wav(PathBuf::from(path)),
+                Some(("segments", "")) => bail!("segments: needs a directory to write to, like segments:/var/lib/audimon"),
+                Some(("segments", dir)) => Output::Segments(PathBuf::from(dir)),
+                _ => bail!(
+                    "Unknown output {}, expected webrtc, local, none, wav:<path> or segments:<directory>",
+                    output
+                ),
+            },
+        })
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Output::Webrtc => write!(f, "webrtc"),
+            Output::Local => write!(f, "local"),
+            Output::Wav(path) => write!(f, "wav:{}", path.display()),
+            Output::Segments(dir) => write!(f, "segments:{}", dir.display()),
+            Output::None => write!(f, "none"),
+        }
+    }
+}
+
+// `output` can be one output or a list of them
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<Output>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged, expecting = "an output, or a list of outputs")]
+    enum Outputs {
+        One(String),
+        Many(Vec<String>),
+    }
+    let outputs = match Outputs::deserialize(deserializer)? {
+        Outputs::One(output) => vec![output],
+        Outputs::Many(outputs) => outputs,
+    };
+    outputs.iter().map(|output| output.parse().map_err(de::Error::custom)).collect()
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SinksSection {
+    // Every frame goes to all of these at once
+    #[serde(rename = "output", deserialize_with = "one_or_many")]
+    pub outputs: Vec<Output>,
+    pub webrtc: WebrtcSection,
+    pub segments: SegmentsSection,
+}
+
+impl SinksSection {
+    pub fn makes_audio(&self) -> bool {
+        !self.outputs.contains(&Output::None)
+    }
+}
+
+impl Default for SinksSection {
+    fn default() -> SinksSection {
+        SinksSection {
+            outputs: vec![Output::Webrtc],
+            webrtc: WebrtcSection::default(),
+            segments: SegmentsSection::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebrtcSection {
+    pub stun_servers: Vec<String>,
+    // More STUN or TURN servers, for the ones that need credentials
+    pub ice_servers: Vec<IceServer>,
+    // Only offer our own addresses, using no STUN or TURN servers at all, for when
+    // listeners are on the same network or there's no internet to reach the servers
+    pub host_only: bool,
+    // The lowest and highest UDP port for peer connections, any free port when left out
+    pub udp_ports: Option<[u16; 2]>,
+    // Where to answer listeners' offers over HTTP, WHEP style. Offers are pasted on stdin
+    // when left out.
+    pub listen: Option<SocketAddr>,
+    // More than this many at once are turned away, since each takes sockets and encryption
+    pub max_listeners: usize,
+}
+
+impl Default for WebrtcSection {
+    fn default() -> WebrtcSection {
+        WebrtcSection {
+            stun_servers: vec!["stun:stun.l.google.com:19302".to_owned()],
+            ice_servers: Vec::new(),
+            host_only: false,
+            udp_ports: None,
+            listen: None,
+            max_listeners: 32,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IceServer {
+    // Like "turn:turn.example.com:3478?transport=udp"
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub credential: String,
+}
+
+// Settings are logged when they change, so leave the credential out
+impl Debug for IceServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IceServer")
+            .field("urls", &self.urls)
+            .field("username", &self.username)
+            .field("credential", &if self.credential.is_empty() { "" } else { "..." })
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SegmentsSection {
+    // How long each file is
+    pub segment_minutes: u64,
+    // Files that ended longer ago than this are deleted
+    pub retention_hours: u64,
+}
+
+impl Default for SegmentsSection {
+    fn default() -> SegmentsSection {
+        SegmentsSection {
+            segment_minutes: 60,
+            retention_hours: 24 * 7,
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlSection {
+    // Where `daemon ctl` finds us, off when left out and there's no per-user runtime dir.
+    // Anyone who can write to the socket's directory can poke at the daemon.
+    pub socket: Option<PathBuf>,
+}
+
+impl Default for ControlSection {
+    fn default() -> ControlSection {
+        ControlSection {
+            socket: std::env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("audimon.sock")),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OscSection {
+    // Where to listen for OSC control messages, off when left out
+    pub listen: Option<SocketAddr>,
+    // Where to send metrics as OSC, off when left out
+    pub send_to: Option<SocketAddr>,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MidiSection {
+    // Where to record metrics as a Standard MIDI File, off when left out
+    pub record: Option<PathBuf>,
+    // Levels over this hold a note, in (0, 1)
+    pub threshold: f32,
+}
+
+impl Default for MidiSection {
+    fn default() -> MidiSection {
+        MidiSection {
+            record: None,
+            threshold: 0.8,
+        }
+    }
+}
+
+impl Config {
+    // Not validated, since flags can still fix it
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+        let config: Config = toml::from_str(&text).with_context(|| format!("Couldn't parse {}", path.display()))?;
+        Ok(config)
+    }
+
+    // Once the flags are applied on top of the file
+    pub fn validate(&self) -> Result<()> {
+        let audio_config = self.audio_config()?;
+        let outputs = &self.sinks.outputs;
+        if outputs.is_empty() {
+            bail!("sinks.output needs at least one output, or \"none\"");
+        }
+        if outputs.len() > 1 && outputs.contains(&Output::None) {
+            bail!("sinks.output can't have \"none\" with other outputs");
+        }
+        for (num, output) in outputs.iter().enumerate() {
+            if outputs[..num].contains(output) {
+                bail!("sinks.output has {} twice", output);
+            }
+        }
+        if outputs.iter().any(|output| matches!(output, Output::Webrtc | Output::Segments(_))) {
+            audio_config.check_opus()?;
+        }
+        let webrtc = &self.sinks.webrtc;
+        for url in webrtc.stun_servers.iter() {
+            if !url.starts_with("stun:") && !url.starts_with("stuns:") {
+                bail!("sinks.webrtc.stun_servers should be stun: or stuns: URLs, not {}", url);
+            }
+        }
+        for server in webrtc.ice_servers.iter() {
+            if server.urls.is_empty() {
+                bail!("sinks.webrtc.ice_servers needs urls for each server");
+            }
+            for url in server.urls.iter() {
+                let turn = url.starts_with("turn:") || url.starts_with("turns:");
+                if !turn && !url.starts_with("stun:") && !url.starts_with("stuns:") {
+                    bail!("sinks.webrtc.ice_servers should be stun:, stuns:, turn: or turns: URLs, not {}", url);
+                }
+                if turn && (server.username.is_empty() || server.credential.is_empty()) {
+                    bail!("sinks.webrtc.ice_servers needs a username and credential for {}", url);
+                }
+            }
+        }
+        if let Some([min, max]) = webrtc.udp_ports {
+            if min == 0 || min > max {
+                bail!("sinks.webrtc.udp_ports must be the lowest then the highest port, not {} and {}", min, max);
+            }
+        }
+        if webrtc.max_listeners == 0 {
+            bail!("sinks.webrtc.max_listeners must be more than 0");
+        }
+        if self.sinks.segments.segment_minutes == 0 {
+            bail!("sinks.segments.segment_minutes must be more than 0");
+        }
+        if self.sinks.segments.retention_hours == 0 {
+            bail!("sinks.segments.retention_hours must be more than 0, or every finished segment would be deleted");
+        }
+        if !self.sinks.makes_audio() && self.osc.send_to.is_none() && self.midi.record.is_none() {
+            bail!("With sinks.output = \"none\", no osc.send_to and no midi.record there'd be nothing to do");
+        }
+        let poll_ms = &self.sources.poll_ms;
+        for (source, ms) in [
+            ("cpu", poll_ms.cpu),
+            ("memory", poll_ms.memory),
+            ("network", poll_ms.network),
+            ("disk", poll_ms.disk),
+            ("processes", poll_ms.processes),
+        ] {
+            if ms == 0 {
+                bail!("sources.poll_ms.{} must be more than 0", source);
+            }
+        }
+        let smoothing = self.mapping.smoothing;
+        if smoothing.is_nan() || smoothing <= 0. || smoothing > 1. {
+            bail!("mapping.smoothing must be more than 0 and at most 1, not {}", smoothing);
+        }
+        let threshold = self.midi.threshold;
+        if threshold.is_nan() || threshold <= 0. || threshold >= 1. {
+            bail!("midi.threshold must be between 0 and 1, not {}", threshold);
+        }
+        Ok(())
+    }
+
+    // Every setting that differs, one line each, for logging reloads
+    pub fn changes(&self, new: &Config) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut compare = |name: &str, old: &dyn Debug, new: &dyn Debug| {
+            let (old, new) = (format!("{:?}", old), format!("{:?}", new));
+            if old != new {
+                changes.push(format!("{}: {} -> {}", name, old, new));
+            }
+        };
+        compare("audio.sample_rate", &self.audio.sample_rate, &new.audio.sample_rate);
+        compare("audio.frame_ms", &self.audio.frame_ms, &new.audio.frame_ms);
+        compare("sources.per_core", &self.sources.per_core, &new.sources.per_core);
+        compare("sources.drones_by", &self.sources.drones_by, &new.sources.drones_by);
+        compare("sources.net_include", &self.sources.net_include, &new.sources.net_include);
+        compare("sources.net_exclude", &self.sources.net_exclude, &new.sources.net_exclude);
+        compare("sources.poll_ms.cpu", &self.sources.poll_ms.cpu, &new.sources.poll_ms.cpu);
+        compare("sources.poll_ms.memory", &self.sources.poll_ms.memory, &new.sources.poll_ms.memory);
+        compare("sources.poll_ms.network", &self.sources.poll_ms.network, &new.sources.poll_ms.network);
+        compare("sources.poll_ms.disk", &self.sources.poll_ms.disk, &new.sources.poll_ms.disk);
+        compare("sources.poll_ms.processes", &self.sources.poll_ms.processes, &new.sources.poll_ms.processes);
+        compare("mapping.smoothing", &self.mapping.smoothing, &new.mapping.smoothing);
+        for name in self.dsp.keys().chain(new.dsp.keys()).collect::<BTreeSet<&String>>() {
+            compare(&format!("dsp.{}", name), &self.dsp.get(name), &new.dsp.get(name));
+        }
+        compare("sinks.output", &self.sinks.outputs, &new.sinks.outputs);
+        compare("sinks.webrtc.stun_servers", &self.sinks.webrtc.stun_servers, &new.sinks.webrtc.stun_servers);
+        compare("sinks.webrtc.ice_servers", &self.sinks.webrtc.ice_servers, &new.sinks.webrtc.ice_servers);
+        compare("sinks.webrtc.host_only", &self.sinks.webrtc.host_only, &new.sinks.webrtc.host_only);
+        compare("sinks.webrtc.udp_ports", &self.sinks.webrtc.udp_ports, &new.sinks.webrtc.udp_ports);
+        compare("sinks.webrtc.listen", &self.sinks.webrtc.listen, &new.sinks.webrtc.listen);
+        compare("sinks.webrtc.max_listeners", &self.sinks.webrtc.max_listeners, &new.sinks.webrtc.max_listeners);
+        compare("sinks.segments.segment_minutes", &self.sinks.segments.segment_minutes, &new.sinks.segments.segment_minutes);
+        compare("sinks.segments.retention_hours", &self.sinks.segments.retention_hours, &new.sinks.segments.retention_hours);
+        compare("control.socket", &self.control.socket, &new.control.socket);
+        compare("osc.listen", &self.osc.listen, &new.osc.listen);
+        compare("osc.send_to", &self.osc.send_to, &new.osc.send_to);
+        compare("midi.record", &self.midi.record, &new.midi.record);
+        compare("midi.threshold", &self.midi.threshold, &new.midi.threshold);
+        changes
+    }
+
+    // Audio and sink settings are baked into running streams and peer connections, and
+    // the control sockets are already bound and the recording started
+    pub fn needs_restart(&self, new: &Config) -> bool {
+        self.audio != new.audio
+            || self.sinks != new.sinks
+            || self.control != new.control
+            || self.osc != new.osc
+            || self.midi != new.midi
+    }
+
+    pub fn audio_config(&self) -> Result<AudioConfig> {
+        if self.audio.frame_ms.is_nan() || self.audio.frame_ms <= 0. {
+            bail!("audio.frame_ms must be more than 0");
+        }
+        AudioConfig::new(
+            self.audio.sample_rate,
+            Duration::from_micros((self.audio.frame_ms * 1000.).round() as u64),
+        )
+    }
+
+    pub fn source_options(&self) -> SourceOptions {
+        let poll_ms = &self.sources.poll_ms;
+        SourceOptions {
+            per_core: self.sources.per_core,
+            drones_by: self.sources.drones_by,
+            net_include: self.sources.net_include.clone(),
+            net_exclude: self.sources.net_exclude.clone(),
+            poll_intervals: PollIntervals {
+                cpu: Duration::from_millis(poll_ms.cpu),
+                memory: Duration::from_millis(poll_ms.memory),
+                network: Duration::from_millis(poll_ms.network),
+                disk: Duration::from_millis(poll_ms.disk),
+                processes: Duration::from_millis(poll_ms.processes),
+            },
+            smoothing: self.mapping.smoothing,
+        }
+    }
+
+    // The running options with only the settings that differ between the two files
+    // changed, so ones set over OSC since and left alone in the file stay put. None when
+    // no source setting changed.
+    pub fn changed_source_options(&self, new: &Config, running: &SourceOptions) -> Option<SourceOptions> {
+        fn take<T: PartialEq + Clone>(old: &T, new: &T, running: &mut T) -> bool {
+            if old == new {
+                return false;
+            }
+            *running = new.clone();
+            true
+        }
+        let (old, new) = (self.source_options(), new.source_options());
+        let mut options = running.clone();
+        let changed = [
+            take(&old.per_core, &new.per_core, &mut options.per_core),
+            take(&old.drones_by, &new.drones_by, &mut options.drones_by),
+            take(&old.net_include, &new.net_include, &mut options.net_include),
+            take(&old.net_exclude, &new.net_exclude, &mut options.net_exclude),
+            take(&old.poll_intervals.cpu, &new.poll_intervals.cpu, &mut options.poll_intervals.cpu),
+            take(&old.poll_intervals.memory, &new.poll_intervals.memory, &mut options.poll_intervals.memory),
+            take(&old.poll_intervals.network, &new.poll_intervals.network, &mut options.poll_intervals.network),
+            take(&old.poll_intervals.disk, &new.poll_intervals.disk, &mut options.poll_intervals.disk),
+            take(&old.poll_intervals.processes, &new.poll_intervals.processes, &mut options.poll_intervals.processes),
+            take(&old.smoothing, &new.smoothing, &mut options.smoothing),
+        ];
+        changed.contains(&true).then_some(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn invalid(toml: &str) -> String {
+        format!("{:#}", config(toml).validate().unwrap_err())
+    }
+
+    #[test]
+    fn parses_outputs() {
+        assert_eq!("webrtc".parse::<Output>().unwrap(), Output::Webrtc);
+        assert_eq!("local".parse::<Output>().unwrap(), Output::Local);
+        assert_eq!("none".parse::<Output>().unwrap(), Output::None);
+        assert_eq!("wav:a.wav".parse::<Output>().unwrap(), Output::Wav(PathBuf::from("a.wav")));
+        assert_eq!("segments:/tmp/a".parse::<Output>().unwrap(), Output::Segments(PathBuf::from("/tmp/a")));
+        // Only the first colon separates
+        assert_eq!("wav:C:/a.wav".parse::<Output>().unwrap(), Output::Wav(PathBuf::from("C:/a.wav")));
+    }
+
+    #[test]
+    fn rejects_bad_outputs() {
+        for output in ["", "mp3", "wav", "wav:", "segments:", "flac:a.flac", "WEBRTC"] {
+            assert!(output.parse::<Output>().is_err(), "{} parsed", output);
+        }
+    }
+
+    #[test]
+    fn outputs_round_trip() {
+        for output in ["webrtc", "local", "none", "wav:a.wav", "segments:/tmp/a"] {
+            assert_eq!(output.parse::<Output>().unwrap().to_string(), output);
+        }
+    }
+
+    #[test]
+    fn output_is_one_or_many() {
+        assert_eq!(config("[sinks]\noutput = \"local\"").sinks.outputs, vec![Output::Local]);
+        assert_eq!(
+            config("[sinks]\noutput = [\"local\", \"wav:a.wav\"]").sinks.outputs,
+            vec![Output::Local, Output::Wav(PathBuf::from("a.wav"))]
+        );
+        assert!(toml::from_str::<Config>("[sinks]\noutput = \"mp3\"").is_err());
+        assert!(toml::from_str::<Config>("[sinks]\noutput = 1").is_err());
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        Config::default().validate().unwrap();
+        config("").validate().unwrap();
+    }
+
+    #[test]
+    fn checks_outputs() {
+        assert!(invalid("[sinks]\noutput = []").contains("at least one output"));
+        assert!(invalid("[sinks]\noutput = [\"none\", \"local\"]").contains("\"none\" with other outputs"));
+        assert!(invalid("[sinks]\noutput = [\"local\", \"local\"]").contains("local twice"));
+        assert!(invalid("[sinks]\noutput = \"none\"").contains("nothing to do"));
+        config("[sinks]\noutput = \"none\"\n[osc]\nsend_to = \"127.0.0.1:57120\"").validate().unwrap();
+        config("[sinks]\noutput = \"none\"\n[midi]\nrecord = \"a.mid\"").validate().unwrap();
+    }
+
+    #[test]
+    fn only_opus_outputs_need_opus_rates() {
+        assert!(invalid("[audio]\nsample_rate = 44100\nframe_ms = 10").contains("Opus"));
+        assert!(invalid("[audio]\nsample_rate = 44100\nframe_ms = 10\n[sinks]\noutput = \"segments:a\"").contains("Opus"));
+        config("[audio]\nsample_rate = 44100\nframe_ms = 10\n[sinks]\noutput = [\"local\", \"wav:a.wav\"]")
+            .validate()
+            .unwrap();
+        assert!(invalid("[audio]\nframe_ms = 0").contains("frame_ms"));
+        assert!(invalid("[audio]\nsample_rate = 44100\nframe_ms = 1.5\n[sinks]\noutput = \"local\"").contains("whole number"));
+    }
+
+    #[test]
+    fn checks_webrtc() {
+        assert!(invalid("[sinks.webrtc]\nstun_servers = [\"turn:a\"]").contains("stun_servers"));
+        assert!(invalid("[[sinks.webrtc.ice_servers]]\nurls = []").contains("needs urls"));
+        assert!(invalid("[[sinks.webrtc.ice_servers]]\nurls = [\"http://a\"]").contains("not http://a"));
+        assert!(invalid("[[sinks.webrtc.ice_servers]]\nurls = [\"turn:a\"]").contains("credential"));
+        config("[[sinks.webrtc.ice_servers]]\nurls = [\"turn:a\"]\nusername = \"u\"\ncredential = \"c\"")
+            .validate()
+            .unwrap();
+        config("[[sinks.webrtc.ice_servers]]\nurls = [\"stun:a\"]").validate().unwrap();
+        assert!(invalid("[sinks.webrtc]\nudp_ports = [50100, 50000]").contains("udp_ports"));
+        assert!(invalid("[sinks.webrtc]\nudp_ports = [0, 50000]").contains("udp_ports"));
+        config("[sinks.webrtc]\nudp_ports = [50000, 50000]").validate().unwrap();
+        assert!(invalid("[sinks.webrtc]\nmax_listeners = 0").contains("max_listeners"));
+    }
+
+    #[test]
+    fn checks_numbers() {
+        assert!(invalid("[sinks.segments]\nsegment_minutes = 0").contains("segment_minutes"));
+        assert!(invalid("[sinks.segments]\nretention_hours = 0").contains("retention_hours"));
+        assert!(invalid("[sources.poll_ms]\ndisk = 0").contains("poll_ms.disk"));
+        assert!(invalid("[mapping]\nsmoothing = 0").contains("smoothing"));
+        assert!(invalid("[mapping]\nsmoothing = 1.5").contains("smoothing"));
+        assert!(invalid("[mapping]\nsmoothing = nan").contains("smoothing"));
+        config("[mapping]\nsmoothing = 1").validate().unwrap();
+        assert!(invalid("[midi]\nthreshold = 0").contains("threshold"));
+        assert!(invalid("[midi]\nthreshold = 1").contains("threshold"));
+    }
+
+    #[test]
+    fn reloads_only_change_what_changed_in_the_file() {
+        let old = config("[sources]\nper_core = false\n[sources.poll_ms]\ncpu = 100");
+        let mut running = old.source_options();
+        // Changed over OSC
+        running.drones_by = DroneMetric::Memory;
+        running.poll_intervals.disk = Duration::from_millis(1000);
+        assert!(old.changed_source_options(&config("[sources]\nper_core = false\n[sources.poll_ms]\ncpu = 100"), &running).is_none());
+        let options = old
+            .changed_source_options(&config("[sources]\nper_core = true\n[sources.poll_ms]\ncpu = 100"), &running)
+            .unwrap();
+        assert!(options.per_core);
+        assert_eq!(options.drones_by, DroneMetric::Memory);
+        assert_eq!(options.poll_intervals.disk, Duration::from_millis(1000));
+        assert_eq!(options.poll_intervals.cpu, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn ice_credentials_stay_out_of_the_logs() {
+        let server = IceServer { urls: vec!["turn:a".to_owned()], username: "u".to_owned(), credential: "secret".to_owned() };
+        assert!(!format!("{:?}", server).contains("secret"));
+    }
+}
@@ -0,0 +1,137 @@
+// A line protocol on a unix socket for poking at the running daemon, and the `ctl`
+// subcommand that talks it. Each request is one line:
+//   list               every DSP parameter, as `<path> <value> [<min> <max>]`
+//   get <name>         one parameter's value
+//   set <name> <value> set a parameter, taking effect on the next frame
+//   stats              local playback's `underruns <n>` and `overruns <n>` so far
+// Replies are any number of data lines, then `ok` or `error <why>`.
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use crate::audio::{self, DspState};
+use crate::local_sink::Glitches;
+
+// What the commands can look at, cloned for each client
+#[derive(Clone)]
+pub struct Controls {
+    pub dsp_state: Option<DspState>,
+    // Only there when playing locally
+    pub glitches: Option<Arc<Glitches>>,
+}
+
+fn run_command(line: &str, controls: &Controls) -> Result<Vec<String>> {
+    let words = line.split_whitespace().collect::<Vec<&str>>();
+    if words[..] == ["stats"] {
+        return match &controls.glitches {
+            Some(glitches) => Ok(vec![
+                format!("underruns {}", glitches.underruns()),
+                format!("overruns {}", glitches.overruns()),
+            ]),
+            None => bail!("Not playing locally, so there are no playback stats"),
+        };
+    }
+    let mut state = audio::lock_dsp(&controls.dsp_state)?;
+    match words[..] {
+        ["list"] => Ok(audio::list_dsp_params(&state)
+            .into_iter()
+            .map(|param| match param.range {
+                Some((min, max)) => format!("{} {} {} {}", param.path, param.value, min, max),
+                None => format!("{} {}", param.path, param.value),
+            })
+            .collect()),
+        ["get", name] => Ok(vec![audio::get_dsp_param(&state, name)?.to_string()]),
+        ["set", name, value] => {
+            let value = value.parse::<f32>().map_err(|_| anyhow::Error::msg(format!("{} isn't a number", value)))?;
+            audio::set_dsp_params(&mut state, &BTreeMap::from([(name.to_owned(), value)]))?;
+            Ok(vec![])
+        }
+        _ => bail!("Unknown command, try list, get <name>, set <name> <value> or stats"),
+    }
+}
+
+async fn serve_client(stream: UnixStream, controls: Controls) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match run_command(&line, &controls) {
+            Ok(data) => data.into_iter().map(|line| line + "\n").collect::<String>() + "ok\n",
+            Err(err) => format!("error {:#}\n", err),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+// The socket file is removed when this is dropped
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub fn serve(path: PathBuf, controls: Controls) -> Result<ControlSocket> {
+    // A socket left behind by a previous run would make bind fail, but one that answers
+    // belongs to a daemon that's still running
+    if let Ok(metadata) = fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and isn't a socket, not replacing it", path.display());
+        }
+        match std::os::unix::net::UnixStream::connect(&path) {
+            Ok(_) => bail!(
+                "Another daemon is already listening on {}, set control.socket to use a different one",
+                path.display()
+            ),
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => fs::remove_file(&path)?,
+            Err(err) => bail!("Couldn't check whether {} is still in use: {}", path.display(), err),
+        }
+    }
+    let listener = UnixListener::bind(&path)
+        .map_err(|err| anyhow::Error::msg(format!("Couldn't listen on {}: {}", path.display(), err)))?;
+    println!("Listening for control commands on {}", path.display());
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let controls = controls.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = serve_client(stream, controls).await {
+                            println!("Control client went away: {}", err);
+                        }
+                    });
+                }
+                Err(err) => println!("Couldn't accept control connection: {}", err),
+            }
+        }
+    });
+    Ok(ControlSocket { path })
+}
+
+// Sends one command to a running daemon and prints what comes back
+pub async fn run_client(path: &Path, command: &[String]) -> Result<()> {
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|err| anyhow::Error::msg(format!("Couldn't reach the daemon at {}: {}", path.display(), err)))?;
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all(format!("{}\n", command.join(" ")).as_bytes()).await?;
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line == "ok" {
+            return Ok(());
+        }
+        if let Some(err) = line.strip_prefix("error ") {
+            bail!("{}", err);
+        }
+        println!("{}", line);
+    }
+    bail!("The daemon hung up without answering")
+}
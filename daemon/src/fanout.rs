@@ -0,0 +1,106 @@
+// Hands every rendered frame to each sink on a queue of its own. A sink that falls behind
+// loses frames once its queue is full rather than holding up the renderer or the others.
+use anyhow::Result;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// About a tenth of a second at the default 20ms frames, for each sink and again between
+// a recording sink and its writer
+const QUEUE_FRAMES: usize = 5;
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+pub type Frame = Vec<(i16, i16)>;
+
+struct Feed {
+    name: String,
+    frames: mpsc::Sender<Frame>,
+    dropped: u64,
+    reported: u64,
+    last_report: Option<Instant>,
+}
+
+impl Feed {
+    fn report_drops(&mut self) {
+        if self.dropped == self.reported || self.last_report.is_some_and(|at| at.elapsed() < DROP_REPORT_INTERVAL) {
+            return;
+        }
+        println!("{} can't keep up, frames dropped so far: {}", self.name, self.dropped);
+        self.reported = self.dropped;
+        self.last_report = Some(Instant::now());
+    }
+}
+
+#[derive(Default)]
+pub struct Fanout {
+    feeds: Vec<Feed>,
+}
+
+impl Fanout {
+    // A queue for another sink, called `name` in the logs
+    pub fn add(&mut self, name: &str) -> mpsc::Receiver<Frame> {
+        let (frames, receiver) = mpsc::channel(QUEUE_FRAMES);
+        self.feeds.push(Feed {
+            name: name.to_owned(),
+            frames,
+            dropped: 0,
+            reported: 0,
+            last_report: None,
+        });
+        receiver
+    }
+
+    // False once every sink has shut down
+    pub fn send(&mut self, frame: Frame) -> bool {
+        self.feeds.retain_mut(|feed| {
+            match feed.frames.try_send(frame.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => feed.dropped += 1,
+                Err(TrySendError::Closed(_)) => {
+                    println!("{} has stopped", feed.name);
+                    return false;
+                }
+            }
+            feed.report_drops();
+            true
+        });
+        !self.feeds.is_empty()
+    }
+}
+
+// For sinks whose writing blocks: runs `write` on a thread of its own, handing it frames
+// until they run out, `write` stops taking them or `shutdown` goes true. Then its frames
+// stop coming, so it can finish what it's writing. The task ends when `write` does.
+pub fn spawn_blocking_writer<W>(
+    mut frames: mpsc::Receiver<Frame>,
+    mut shutdown: watch::Receiver<bool>,
+    write: W,
+) -> JoinHandle<Result<()>>
+where
+    W: FnOnce(mpsc::Receiver<Frame>) -> Result<()> + Send + 'static,
+{
+    let (writer_tx, writer_rx) = mpsc::channel(QUEUE_FRAMES);
+    let writer = tokio::task::spawn_blocking(move || write(writer_rx));
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                frame = frames.recv() => {
+                    let frame = match frame {
+                        Some(frame) => frame,
+                        None => break,
+                    };
+                    // Fails once the writer has stopped
+                    if writer_tx.send(frame).await.is_err() {
+                        break;
+                    }
+                }
+                _ = shutdown.changed() => break,
+            }
+        }
+        // Takes the sink out of the fan-out, and lets the writer finish
+        drop(frames);
+        drop(writer_tx);
+        writer.await?
+    })
+}
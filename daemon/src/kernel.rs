@@ -0,0 +1,96 @@
+// Readers for kernel statistics that sysinfo doesn't give us.
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// /proc/diskstats counts in 512 byte sectors regardless of the device's sector size
+pub const DISKSTATS_SECTOR_SIZE: u64 = 512;
+
+// Cumulative pages swapped in and out since boot
+#[derive(Clone, Copy)]
+pub struct SwapActivity {
+    pub pages_in: u64,
+    pub pages_out: u64,
+}
+
+pub fn read_swap_activity() -> Option<SwapActivity> {
+    let vmstat = fs::read_to_string("/proc/vmstat").ok()?;
+    let mut pages_in = None;
+    let mut pages_out = None;
+    for line in vmstat.lines() {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some("pswpin"), Some(value)) => pages_in = value.parse().ok(),
+            (Some("pswpout"), Some(value)) => pages_out = value.parse().ok(),
+            _ => {}
+        }
+    }
+    Some(SwapActivity {
+        pages_in: pages_in?,
+        pages_out: pages_out?,
+    })
+}
+
+// Cumulative I/O counters for one block device
+#[derive(Clone, Copy, Default)]
+pub struct DiskActivity {
+    pub reads: u64,
+    pub sectors_read: u64,
+    pub writes: u64,
+    pub sectors_written: u64,
+}
+
+fn is_physical_block_device(name: &str) -> bool {
+    // Partitions, loop devices, device mapper and md arrays don't have a backing device,
+    // so this avoids counting the same I/O more than once.
+    Path::new("/sys/block")
+        .join(name.replace('/', "!"))
+        .join("device")
+        .exists()
+}
+
+pub fn read_disk_activity() -> Option<HashMap<String, DiskActivity>> {
+    let diskstats = fs::read_to_string("/proc/diskstats").ok()?;
+    let mut devices = HashMap::new();
+    for line in diskstats.lines() {
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() < 10 || !is_physical_block_device(fields[2]) {
+            continue;
+        }
+        let field = |i: usize| fields[i].parse::<u64>().unwrap_or(0);
+        devices.insert(
+            fields[2].to_owned(),
+            DiskActivity {
+                reads: field(3),
+                sectors_read: field(5),
+                writes: field(7),
+                sectors_written: field(9),
+            },
+        );
+    }
+    Some(devices)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterfaceKind {
+    Loopback,
+    // Backed by actual hardware
+    Physical,
+    // Bridges, veths, tunnels and friends
+    Virtual,
+}
+
+// ARPHRD_LOOPBACK from linux/if_arp.h
+const ARPHRD_LOOPBACK: &str = "772";
+
+pub fn classify_interface(name: &str) -> InterfaceKind {
+    let sys_path = Path::new("/sys/class/net").join(name);
+    let interface_type = fs::read_to_string(sys_path.join("type")).unwrap_or_default();
+    if name == "lo" || interface_type.trim() == ARPHRD_LOOPBACK {
+        InterfaceKind::Loopback
+    } else if sys_path.join("device").exists() {
+        InterfaceKind::Physical
+    } else {
+        InterfaceKind::Virtual
+    }
+}
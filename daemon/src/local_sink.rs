@@ -7,17 +7,80 @@ extern crate clap;
 extern crate cpal;
 
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use dasp::sample::Sample;
 use dasp::ring_buffer::Bounded as RB;
+use tokio::sync::{Notify, Semaphore};
+use crate::audio::AudioConfig;
+
+// Ask for another frame once fewer than this many frames are queued
+const LOW_WATER_FRAMES: usize = 2;
+// Enough for the low water mark, a frame being rendered and one more asked for meanwhile
+const BUFFER_FRAMES: usize = LOW_WATER_FRAMES + 3;
+const STATS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+// How often playback has glitched, readable with `ctl stats` while the daemon runs
+#[derive(Default)]
+pub struct Glitches {
+    // Callbacks that ran out of samples and played silence
+    underruns: AtomicU64,
+    // Frames that didn't fit in the buffer and pushed out older samples
+    overruns: AtomicU64,
+}
+
+impl Glitches {
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+}
+
+// The cpal callback pulls samples and asks the renderer for more through `demand`, one
+// permit per frame
+struct Playback {
+    buffer: Mutex<(RB<Vec<f32>>, RB<Vec<f32>>)>,
+    demand: Arc<Semaphore>,
+    frame_size: usize,
+    low_water: usize,
+    // Frames asked for that haven't arrived yet
+    pending: AtomicUsize,
+    glitches: Arc<Glitches>,
+    // Told when the stream fails, like the device being unplugged
+    failed: Notify,
+}
+
+// Starts out empty, so the first frames aren't counted as overruns
+fn empty_ring(capacity: usize) -> RB<Vec<f32>> {
+    let mut ring = RB::from(vec![0f32; capacity]);
+    while ring.pop().is_some() {}
+    ring
+}
 
+// Plays the frames on the default output device. If the stream fails we stop taking frames
+// and close `demand`, so rendering goes back to its own timer for the other sinks.
 pub async fn local_sink(
     mut audio_pipe: tokio::sync::mpsc::Receiver<Vec<(i16, i16)>>,
-    _done_tx: tokio::sync::mpsc::Sender<()>
-) -> Result<(), anyhow::Error> {
-    let buffer =  (RB::from([0f32; 2048]), RB::from([0f32; 2048]));
-    let buf_ref_1 = Arc::new(Mutex::new(buffer));
-    let buf_ref_2 = buf_ref_1.clone();
+    audio_config: AudioConfig,
+    demand: Arc<Semaphore>,
+    glitches: Arc<Glitches>,
+) -> Result<cpal::Stream, anyhow::Error> {
+    let capacity = audio_config.frame_size() * BUFFER_FRAMES;
+    let playback = Arc::new(Playback {
+        buffer: Mutex::new((empty_ring(capacity), empty_ring(capacity))),
+        demand,
+        frame_size: audio_config.frame_size(),
+        low_water: audio_config.frame_size() * LOW_WATER_FRAMES,
+        pending: AtomicUsize::new(0),
+        glitches,
+        failed: Notify::new(),
+    });
+    let buf_ref_1 = playback.clone();
+    let buf_ref_2 = playback.clone();
+    let err_ref = playback.clone();
 
     let host = cpal::default_host();
 
@@ -26,20 +89,26 @@ pub async fn local_sink(
         .ok_or_else(|| anyhow::Error::msg("Default output device is not available"))?;
     println!("Output device : {}", device.name()?);
 
-    //let config = device.default_output_config()?;
-    let mut config = device.default_output_config()?;
-    for config_range in device.supported_output_configs()? {
-        let target = cpal::SampleRate(48000);
-        if (config_range.min_sample_rate() <= target) && (config_range.max_sample_rate() >= target) {
-            config = config_range.with_sample_rate(cpal::SampleRate(48000));
-        }
-    }
+    // We don't resample, so the device has to run at our rate
+    let target = cpal::SampleRate(audio_config.sample_rate);
+    let config = device
+        .supported_output_configs()?
+        .filter(|config_range| config_range.min_sample_rate() <= target && config_range.max_sample_rate() >= target)
+        .last()
+        .map(|config_range| config_range.with_sample_rate(target))
+        .ok_or_else(|| {
+            anyhow::Error::msg(format!("Output device doesn't support {} Hz", audio_config.sample_rate))
+        })?;
 
     println!("Default output config : {:?}", config);
 
     let nchannels = config.channels() as usize;
 
-    let err_fn = |err| eprintln!("Error building output sound stream: {}", err);
+    let err_fn = move |err| {
+        eprintln!("Local audio stream failed, stopped playing: {}", err);
+        err_ref.demand.close();
+        err_ref.failed.notify_one();
+    };
 
     let stream = match config.sample_format() {
         cpal::SampleFormat::F32 => device.build_output_stream(
@@ -68,52 +137,85 @@ pub async fn local_sink(
     stream.play()?;
     tokio::spawn(async move {
         loop {
-            let frames = audio_pipe.recv().await;
-            if let Ok(mut guard) = buf_ref_2.lock() {
-                for frame in frames.iter() {
-                    for msg in frame.iter() {
-                        let out0 = Sample::to_sample::<f32>(
-                            msg.0
-                        );
-                        let out1 = Sample::to_sample::<f32>(
-                            msg.1
-                        );
-                        guard.0.push(Sample::from_sample(out0));
-                        guard.1.push(Sample::from_sample(out1));
-                    }
+            let frame = tokio::select! {
+                frame = audio_pipe.recv() => match frame {
+                    Some(frame) => frame,
+                    None => break,
+                },
+                // Hanging up tells the fan-out we've stopped
+                _ = buf_ref_2.failed.notified() => break,
+            };
+            let _ = buf_ref_2.pending.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pending| pending.checked_sub(1));
+            if let Ok(mut guard) = buf_ref_2.buffer.lock() {
+                let mut overrun = false;
+                for msg in frame.iter() {
+                    let out0 = Sample::to_sample::<f32>(
+                        msg.0
+                    );
+                    let out1 = Sample::to_sample::<f32>(
+                        msg.1
+                    );
+                    overrun |= guard.0.push(Sample::from_sample(out0)).is_some();
+                    guard.1.push(Sample::from_sample(out1));
+                }
+                if overrun {
+                    buf_ref_2.glitches.overruns.fetch_add(1, Ordering::Relaxed);
                 }
             }
         }
     });
 
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            println!("");
+    let stats = playback.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(STATS_INTERVAL);
+        let mut reported = (0, 0);
+        loop {
+            ticker.tick().await;
+            let glitches = (stats.glitches.underruns(), stats.glitches.overruns());
+            if glitches != reported {
+                println!("Local audio: {} underruns, {} overruns so far", glitches.0, glitches.1);
+                reported = glitches;
+            }
         }
-    };
-    Ok(())
-}
-
-type SharedBufReference = Arc<Mutex<(RB<[f32;2048]>, RB<[f32;2048]>)>>;
+    });
 
+    // Playback stops when the stream is dropped
+    Ok(stream)
+}
 
-fn sampler<T: cpal::Sample>(output: &mut [T], channels: usize, buf_ref: &SharedBufReference) {
+fn sampler<T: cpal::Sample>(output: &mut [T], channels: usize, playback: &Playback) {
     let mut sample_count = 0;
+    let mut underrun = false;
+    // Lock once per callback rather than once per sample
+    let mut guard = playback.buffer.lock().ok();
     for stereo_sample in output.chunks_mut(channels) {
-        let left: f32;
-        let right: f32;
-        if let Ok(mut guard) = buf_ref.lock() {
-            left = guard.0.pop().unwrap_or(0.);
-            right = guard.1.pop().unwrap_or(0.);
-        } else {
-            left = 0.;
-            right = 0.;
-        }
+        let (left, right) = match guard.as_mut().map(|buffer| (buffer.0.pop(), buffer.1.pop())) {
+            Some((Some(left), Some(right))) => (left, right),
+            _ => {
+                underrun = true;
+                (0., 0.)
+            }
+        };
         for sample in stereo_sample.iter_mut() {
             let res = if (sample_count % 2) == 0 { left } else { right };
             *sample = cpal::Sample::from::<f32>(&res);
             sample_count += 1;
         }
     }
+    let queued = guard.map(|buffer| buffer.0.len()).unwrap_or(0);
+    if underrun {
+        playback.glitches.underruns.fetch_add(1, Ordering::Relaxed);
+        // Whatever was asked for didn't make it in time, and may never come if the fan-out
+        // dropped it, so ask afresh
+        playback.pending.store(0, Ordering::Relaxed);
+    }
+    // A callback can take several frames, so ask for as many as it takes to get back
+    // over the low water mark, less the ones already on their way
+    let short = playback.low_water.saturating_sub(queued).div_ceil(playback.frame_size);
+    let pending = playback.pending.load(Ordering::Relaxed);
+    if short > pending {
+        playback.pending.fetch_add(short - pending, Ordering::Relaxed);
+        playback.demand.add_permits(short - pending);
+    }
 }
 
@@ -1,9 +1,26 @@
 mod webrtc_sink;
 mod local_sink;
 mod audio;
+mod config;
+mod control;
+mod osc_control;
+mod osc_sink;
+mod midi_sink;
+mod wav_sink;
+mod segment_sink;
+mod opus;
+mod fanout;
+mod kernel;
+mod metrics;
+mod patchbay;
 
-use anyhow::Result;
-use clap::{Command, AppSettings, Arg};
+use anyhow::{Context, Result};
+use clap::{Command, AppSettings, Arg, ArgMatches};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -17,11 +34,122 @@ async fn main() -> Result<()> {
                 .help("Prints more detailed help information")
                 .long("fullhelp"),
         )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .short('c')
+                .takes_value(true)
+                .help("Read settings from this TOML file (see audimon.toml). Flags override it.")
+        )
+        .arg(
+            Arg::new("control-socket")
+                .long("control-socket")
+                .takes_value(true)
+                .help("Listen for (or with ctl, send) control commands on this unix socket")
+        )
+        .arg(
+            Arg::new("osc-listen")
+                .long("osc-listen")
+                .takes_value(true)
+                .help("Listen for OSC control messages on this UDP address, like 127.0.0.1:9000")
+        )
+        .arg(
+            Arg::new("osc-out")
+                .long("osc-out")
+                .takes_value(true)
+                .help("Send every metric level and event as OSC to this UDP address, like 127.0.0.1:57120")
+        )
+        .arg(
+            Arg::new("webrtc-listen")
+                .long("webrtc-listen")
+                .takes_value(true)
+                .help("Serve a listener page and answer WebRTC listeners over HTTP on this address, like 0.0.0.0:8080, instead of offers pasted on stdin. Tools can POST their offer to /whep.")
+        )
+        .arg(
+            Arg::new("host-only")
+                .long("host-only")
+                .help("Don't use any STUN or TURN servers for WebRTC, only this machine's own addresses. For listeners on the same network, or no internet.")
+        )
+        .arg(
+            Arg::new("udp-ports")
+                .long("udp-ports")
+                .takes_value(true)
+                .help("Only use UDP ports in this range for WebRTC, like 50000-50100")
+        )
+        .arg(
+            Arg::new("record-midi")
+                .long("record-midi")
+                .takes_value(true)
+                .help("Record every metric as a Standard MIDI File at this path, saved as it goes and on ctrl-c")
+        )
+        .arg(
+            Arg::new("no-audio")
+                .long("no-audio")
+                .help("Don't make any sound, only send metrics with --osc-out or record them with --record-midi. The same as --output none")
+        )
+        .arg(
+            Arg::new("param")
+                .long("param")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Start a DSP parameter at a value, as name=value. See `ctl list` for names.")
+        )
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .short('o')
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Where the audio goes: webrtc (the default), local, none, wav:<path> to record it, or segments:<directory> to keep recording it in rotating Ogg/Opus files. Repeat it to send the audio to several places at once.")
+        )
         .arg(
             Arg::new("local")
                 .long("local")
                 .short('l')
-                .help("Output to the local computer")
+                .help("Output to the local computer, the same as --output local")
+        )
+        .arg(
+            Arg::new("per-core")
+                .long("per-core")
+                .help("Give each core (or group of cores) its own voice instead of one averaged CPU tone")
+        )
+        .arg(
+            Arg::new("drones-by")
+                .long("drones-by")
+                .takes_value(true)
+                .possible_values(["cpu", "memory"])
+                .help("Which usage picks the top processes that each hold a continuous voice (default cpu)")
+        )
+        .arg(
+            Arg::new("net-include")
+                .long("net-include")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Only listen to these network interfaces (a trailing * matches a prefix)")
+        )
+        .arg(
+            Arg::new("net-exclude")
+                .long("net-exclude")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Ignore these network interfaces (a trailing * matches a prefix)")
+        )
+        .arg(
+            Arg::new("sample-rate")
+                .long("sample-rate")
+                .takes_value(true)
+                .help("Sample rate in Hz, 48000 by default. WebRTC needs one Opus supports: 8000, 12000, 16000, 24000 or 48000")
+        )
+        .arg(
+            Arg::new("frame-ms")
+                .long("frame-ms")
+                .takes_value(true)
+                .help("Length of each rendered frame in milliseconds, 20 by default. WebRTC needs 2.5, 5, 10, 20, 40 or 60")
+        )
+        .subcommand(
+            Command::new("ctl")
+                .about("Control a running daemon: list, get <name>, set <name> <value> or stats")
+                .arg(Arg::new("command").required(true).multiple_values(true).allow_hyphen_values(true))
         );
 
     let matches = app.clone().get_matches();
@@ -31,14 +159,113 @@ async fn main() -> Result<()> {
         std::process::exit(0);
     }
 
-    let (audio_buf_tx, audio_buf_rx) = tokio::sync::mpsc::channel::<Vec<(i16, i16)>>(1);
+    let config_path = matches.value_of("config").map(PathBuf::from);
+    let mut config = match &config_path {
+        Some(path) => config::Config::load(path)?,
+        None => config::Config::default(),
+    };
+    apply_flags(&mut config, &matches)?;
+
+    if let Some(ctl_matches) = matches.subcommand_matches("ctl") {
+        let command = ctl_matches.values_of("command").unwrap().map(String::from).collect::<Vec<String>>();
+        let socket = config
+            .control
+            .socket
+            .as_deref()
+            .context("No control socket to reach the daemon on, set control.socket or --control-socket")?;
+        return control::run_client(socket, &command).await;
+    }
+
+    config.validate().context("Bad setting in the config file or flags")?;
+    let audio_config = config.audio_config()?;
+
     let (done_tx, mut done_rx) = tokio::sync::mpsc::channel::<()>(1);
+    let (options_tx, options_rx) = watch::channel(config.source_options());
+    // Shared by everything that can change source options while we run
+    let options_tx = Arc::new(options_tx);
+    // Sinks writing files finish them when this goes true, and we wait for them to
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut finishing = Vec::new();
 
-    audio::spawn_audio_thread(audio_buf_tx);
-    if matches.is_present("local") {
-        local_sink::local_sink(audio_buf_rx, done_tx).await.expect("Failed to start local audio.");
+    let mut taps = Vec::new();
+    if let Some(target) = config.osc.send_to {
+        taps.push(osc_sink::osc_sink(target).await?);
+    }
+    if let Some(path) = &config.midi.record {
+        let (tap, recording) = midi_sink::midi_sink(path.clone(), config.midi.threshold, shutdown_rx.clone())?;
+        taps.push(tap);
+        finishing.push(recording);
+    }
+    let sources = patchbay::start_polling(metrics::register_sources(config.source_options()), options_rx, taps);
+    let outputs = config.sinks.outputs.clone();
+    // With local output the sound card's callback asks for each frame, and the other
+    // sinks keep up with that
+    let demand = Arc::new(tokio::sync::Semaphore::new(0));
+    let glitches = Arc::new(local_sink::Glitches::default());
+    let (clock, local_glitches) = if outputs.contains(&config::Output::Local) {
+        (audio::RenderClock::Demand(demand.clone()), Some(glitches.clone()))
+    } else {
+        (audio::RenderClock::Timer, None)
+    };
+    let mut fanout = fanout::Fanout::default();
+    let feeds = outputs
+        .into_iter()
+        .filter(|output| *output != config::Output::None)
+        .map(|output| {
+            let frames = fanout.add(&output.to_string());
+            (output, frames)
+        })
+        .collect::<Vec<_>>();
+    let dsp_state = if feeds.is_empty() {
+        // Without a patchbay to empty them the mailboxes would only fill up, and the
+        // pollers stop posting once they're gone. Taps still get every poll.
+        drop(sources);
+        None
     } else {
-        webrtc_sink::webrtc_sink(audio_buf_rx, done_tx).await.expect("Failed to start webrtc audio.");
+        Some(audio::spawn_audio_thread(fanout, sources, audio_config, clock, &config.dsp, done_tx.clone())?)
+    };
+    let webrtc = config.sinks.webrtc.clone();
+    let segments = config.sinks.segments.clone();
+    let controls = control::Controls { dsp_state: dsp_state.clone(), glitches: local_glitches };
+    // Taking commands is a convenience, so a second daemon on the same socket still plays
+    let _control_socket = config.control.socket.clone().and_then(|path| {
+        control::serve(path, controls)
+            .map_err(|err| println!("Not taking control commands: {:#}", err))
+            .ok()
+    });
+    if let Some(address) = config.osc.listen {
+        osc_control::serve(address, dsp_state.clone(), options_tx.clone()).await?;
+    }
+    tokio::spawn(reload_on_hangup(config_path, matches.clone(), config, options_tx, dsp_state)?);
+
+    // Playing for as long as this is around
+    let mut _local_stream = None;
+    for (output, frames) in feeds {
+        match output {
+            config::Output::Local => {
+                _local_stream = Some(
+                    local_sink::local_sink(frames, audio_config, demand.clone(), glitches.clone())
+                        .await
+                        .expect("Failed to start local audio."),
+                );
+            }
+            config::Output::Webrtc => {
+                webrtc_sink::webrtc_sink(frames, audio_config, webrtc.clone()).await?;
+            }
+            config::Output::Wav(path) => {
+                finishing.push(wav_sink::wav_sink(frames, &path, audio_config, shutdown_rx.clone())?);
+            }
+            config::Output::Segments(dir) => {
+                finishing.push(segment_sink::segment_sink(
+                    frames,
+                    &dir,
+                    audio_config,
+                    &segments,
+                    shutdown_rx.clone(),
+                )?);
+            }
+            config::Output::None => {}
+        }
     }
 
     println!("Press ctrl-c to stop");
@@ -54,5 +281,173 @@ async fn main() -> Result<()> {
         }
     };
 
+    let _ = shutdown_tx.send(true);
+    // One recording failing to finish shouldn't keep the others from finishing
+    let mut failures = Vec::new();
+    for task in finishing {
+        let finished = task.await.unwrap_or_else(|err| Err(err.into()));
+        if let Err(err) = finished {
+            println!("{:#}", err);
+            failures.push(format!("{:#}", err));
+        }
+    }
+    if !failures.is_empty() {
+        anyhow::bail!("Not every recording finished: {}", failures.join("; "));
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+// Command line flags win over the config file
+fn apply_flags(config: &mut config::Config, matches: &ArgMatches) -> Result<()> {
+    // Any outputs given as flags replace the file's
+    let mut outputs = if matches.is_present("output") {
+        matches.values_of_t::<config::Output>("output")?
+    } else {
+        Vec::new()
+    };
+    if matches.is_present("local") && !outputs.contains(&config::Output::Local) {
+        outputs.push(config::Output::Local);
+    }
+    if !outputs.is_empty() {
+        config.sinks.outputs = outputs;
+    }
+    if matches.is_present("per-core") {
+        config.sources.per_core = true;
+    }
+    match matches.value_of("drones-by") {
+        Some("cpu") => config.sources.drones_by = metrics::DroneMetric::Cpu,
+        Some("memory") => config.sources.drones_by = metrics::DroneMetric::Memory,
+        _ => {}
+    }
+    let interface_patterns = |name: &str| -> Option<Vec<String>> {
+        matches.values_of(name).map(|values| values.map(String::from).collect())
+    };
+    if let Some(patterns) = interface_patterns("net-include") {
+        config.sources.net_include = patterns;
+    }
+    if let Some(patterns) = interface_patterns("net-exclude") {
+        config.sources.net_exclude = patterns;
+    }
+    if matches.is_present("sample-rate") {
+        config.audio.sample_rate = matches.value_of_t("sample-rate")?;
+    }
+    if matches.is_present("frame-ms") {
+        config.audio.frame_ms = matches.value_of_t("frame-ms")?;
+    }
+    if let Some(path) = matches.value_of("control-socket") {
+        config.control.socket = Some(PathBuf::from(path));
+    }
+    if matches.is_present("osc-listen") {
+        config.osc.listen = Some(matches.value_of_t("osc-listen")?);
+    }
+    if matches.is_present("osc-out") {
+        config.osc.send_to = Some(matches.value_of_t("osc-out")?);
+    }
+    if matches.is_present("webrtc-listen") {
+        config.sinks.webrtc.listen = Some(matches.value_of_t("webrtc-listen")?);
+    }
+    if matches.is_present("host-only") {
+        config.sinks.webrtc.host_only = true;
+    }
+    if let Some(ports) = matches.value_of("udp-ports") {
+        let (min, max) = ports
+            .split_once('-')
+            .and_then(|(min, max)| Some((min.parse().ok()?, max.parse().ok()?)))
+            .with_context(|| format!("--udp-ports should be like 50000-50100, not {}", ports))?;
+        config.sinks.webrtc.udp_ports = Some([min, max]);
+    }
+    if let Some(path) = matches.value_of("record-midi") {
+        config.midi.record = Some(PathBuf::from(path));
+    }
+    if matches.is_present("no-audio") {
+        config.sinks.outputs = vec![config::Output::None];
+    }
+    for param in matches.values_of("param").into_iter().flatten() {
+        let value = param
+            .split_once('=')
+            .and_then(|(name, value)| Some((name, value.parse::<f32>().ok()?)));
+        match value {
+            Some((name, value)) => {
+                config.dsp.insert(name.to_owned(), value);
+            }
+            None => anyhow::bail!("--param takes name=value, like volume=-6, not {}", param),
+        }
+    }
+    Ok(())
+}
+
+// Re-reads the config file and applies what changed in place, leaving the sinks running
+fn reload_config(
+    path: &Path,
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    options: &watch::Sender<metrics::SourceOptions>,
+    dsp_state: &Option<audio::DspState>,
+) -> Result<()> {
+    let mut new_config = config::Config::load(path)?;
+    apply_flags(&mut new_config, matches)?;
+    new_config.validate().with_context(|| format!("Bad setting in {} or the flags", path.display()))?;
+
+    let changes = config.changes(&new_config);
+    if changes.is_empty() {
+        println!("Reloaded {}, nothing changed", path.display());
+        return Ok(());
+    }
+    for change in changes.iter() {
+        println!("Reloaded {}: {}", path.display(), change);
+    }
+    if config.needs_restart(&new_config) {
+        println!("Audio, sink, control, OSC and recording settings only take effect after a restart, keeping the old ones for now");
+        // So the next reload compares against what's really running
+        new_config.audio = std::mem::take(&mut config.audio);
+        new_config.sinks = std::mem::take(&mut config.sinks);
+        new_config.control = std::mem::take(&mut config.control);
+        new_config.osc = std::mem::take(&mut config.osc);
+        new_config.midi = std::mem::take(&mut config.midi);
+    }
+
+    if let Some(dsp_state) = dsp_state {
+        let mut state = dsp_state.lock().unwrap();
+        let mut changed_params = new_config
+            .dsp
+            .iter()
+            .filter(|(name, value)| config.dsp.get(*name) != Some(*value))
+            .map(|(name, value)| (name.clone(), *value))
+            .collect::<BTreeMap<String, f32>>();
+        // Taken out of the file goes back to where the DSP starts it
+        for name in config.dsp.keys().filter(|name| !new_config.dsp.contains_key(*name)) {
+            changed_params.insert(name.clone(), audio::default_dsp_param(&state, name)?);
+        }
+        audio::set_dsp_params(&mut state, &changed_params)?;
+    }
+    // Sources pick these up before their next poll
+    let changed_options = config.changed_source_options(&new_config, &options.borrow());
+    if let Some(changed_options) = changed_options {
+        let _ = options.send(changed_options);
+    }
+    *config = new_config;
+    Ok(())
+}
+
+fn reload_on_hangup(
+    config_path: Option<PathBuf>,
+    matches: ArgMatches,
+    mut config: config::Config,
+    options: Arc<watch::Sender<metrics::SourceOptions>>,
+    dsp_state: Option<audio::DspState>,
+) -> Result<impl std::future::Future<Output = ()>> {
+    // Listening also stops SIGHUP from killing us when there's nothing to reload
+    let mut hangups = signal(SignalKind::hangup())?;
+    Ok(async move {
+        while hangups.recv().await.is_some() {
+            match &config_path {
+                Some(path) => {
+                    if let Err(err) = reload_config(path, &matches, &mut config, &options, &dsp_state) {
+                        println!("Couldn't reload {}, keeping the current settings: {:#}", path.display(), err);
+                    }
+                }
+                None => println!("Got SIGHUP, but there's no config file to reload. Start with --config to use one."),
+            }
+        }
+    })
+}
@@ -0,0 +1,97 @@
+use std::time::Duration;
+use sysinfo::{ProcessorExt, RefreshKind, System, SystemExt};
+use super::{Channel, LevelRange, MetricSource, Pacing, Reading, SourceOptions};
+
+const NUM_CPU_VOICES: usize = 8;
+
+pub struct CpuSource {
+    system: System,
+    pacing: Pacing,
+    per_core: bool,
+    usage_smooth: f32, // range [0, 1]
+    core_usage_smooth: [f32; NUM_CPU_VOICES], // range [0, 1]
+}
+
+impl CpuSource {
+    pub fn new(pacing: Pacing, per_core: bool) -> CpuSource {
+        CpuSource {
+            system: System::new_with_specifics(RefreshKind::new().with_cpu()),
+            pacing,
+            per_core,
+            usage_smooth: 0.0,
+            core_usage_smooth: [0.0; NUM_CPU_VOICES],
+        }
+    }
+
+    fn get_avg_cpu_usage(&self) -> f32 {
+        let total = self.system.processors().iter().map(|x| x.cpu_usage()).sum::<f32>();
+        let raw = total / self.system.processors().len() as f32;
+        raw / 100.0
+    }
+
+    // Cores are grouped into voices from left to right, each voice following the busiest
+    // core in its group so that one pegged core isn't averaged away.
+    fn get_core_voice_usage(&self) -> [f32; NUM_CPU_VOICES] {
+        let processors = self.system.processors();
+        let num_cores = processors.len();
+        let mut voice_usage = [0f32; NUM_CPU_VOICES];
+        for (i, processor) in processors.iter().enumerate() {
+            let voice = if num_cores == 1 {
+                NUM_CPU_VOICES / 2
+            } else if num_cores <= NUM_CPU_VOICES {
+                // Spread the few cores we have across the whole field
+                i * (NUM_CPU_VOICES - 1) / (num_cores - 1)
+            } else {
+                i * NUM_CPU_VOICES / num_cores
+            };
+            voice_usage[voice] = voice_usage[voice].max(processor.cpu_usage() / 100.0);
+        }
+        voice_usage
+    }
+}
+
+impl MetricSource for CpuSource {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn poll_interval(&self) -> Duration {
+        self.pacing.interval
+    }
+
+    // cpu_load, per_core_mode, then one core_<n> per voice
+    fn channels(&self) -> Vec<Channel> {
+        let mut channels = vec![Channel::level("cpu_load"), Channel::level_in("per_core_mode", LevelRange::Switch)];
+        channels.extend((0..NUM_CPU_VOICES).map(|voice| Channel::level(&format!("core_{}", voice))));
+        channels
+    }
+
+    fn poll(&mut self) -> Vec<Reading> {
+        self.system.refresh_cpu();
+        let avg_cpu_usage = self.get_avg_cpu_usage();
+        if avg_cpu_usage.is_normal() {
+            self.usage_smooth = self.pacing.smear(self.usage_smooth, avg_cpu_usage);
+        };
+        let mut readings = vec![
+            Reading::Level(self.usage_smooth),
+            Reading::Level(if self.per_core { 1. } else { 0. }),
+        ];
+
+        if self.per_core {
+            let voice_usage = self.get_core_voice_usage();
+            for (smooth, usage) in self.core_usage_smooth.iter_mut().zip(voice_usage.iter()) {
+                // Idle cores legitimately sit at zero, so only skip garbage readings
+                if usage.is_finite() {
+                    *smooth = self.pacing.smear(*smooth, *usage);
+                }
+            }
+        }
+        readings.extend(self.core_usage_smooth.iter().map(|usage| Reading::Level(*usage)));
+        readings
+    }
+
+    fn reconfigure(&mut self, options: &SourceOptions) {
+        self.pacing = options.pacing(options.poll_intervals.cpu);
+        self.per_core = options.per_core;
+    }
+}
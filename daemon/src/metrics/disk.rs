@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::kernel;
+use super::{Channel, LevelRange, MetricSource, Pacing, Reading, SourceOptions};
+
+// A fast SSD flat out
+const DISK_BUSY_MB: f32 = 2_000.;
+
+pub struct DiskSource {
+    pacing: Pacing,
+    disk_activity: HashMap<String, kernel::DiskActivity>,
+    disk_activity_time: Instant,
+}
+
+impl DiskSource {
+    pub fn new(pacing: Pacing) -> DiskSource {
+        DiskSource {
+            pacing,
+            disk_activity: kernel::read_disk_activity().unwrap_or_default(),
+            disk_activity_time: Instant::now(),
+        }
+    }
+}
+
+impl MetricSource for DiskSource {
+    fn name(&self) -> &'static str {
+        "disk"
+    }
+
+    fn poll_interval(&self) -> Duration {
+        self.pacing.interval
+    }
+
+    // completed reads and writes, then read and written MB per second
+    fn channels(&self) -> Vec<Channel> {
+        vec![
+            Channel::events("disk_reads", &[]),
+            Channel::events("disk_writes", &[]),
+            Channel::level_in("disk_read_mb", LevelRange::Rate { busy: DISK_BUSY_MB }),
+            Channel::level_in("disk_write_mb", LevelRange::Rate { busy: DISK_BUSY_MB }),
+        ]
+    }
+
+    fn poll(&mut self) -> Vec<Reading> {
+        let mut num_reads = 0;
+        let mut num_writes = 0;
+        let mut sectors_read = 0;
+        let mut sectors_written = 0;
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.disk_activity_time).as_secs_f32();
+        if let Some(new_disk_activity) = kernel::read_disk_activity() {
+            for (device, activity) in new_disk_activity.iter() {
+                // Devices that just showed up have no baseline yet
+                if let Some(prev) = self.disk_activity.get(device) {
+                    num_reads += activity.reads.saturating_sub(prev.reads);
+                    num_writes += activity.writes.saturating_sub(prev.writes);
+                    sectors_read += activity.sectors_read.saturating_sub(prev.sectors_read);
+                    sectors_written += activity.sectors_written.saturating_sub(prev.sectors_written);
+                }
+            }
+            self.disk_activity = new_disk_activity;
+            self.disk_activity_time = now;
+        }
+
+        let to_mb_per_sec = |sectors: u64| {
+            if elapsed > 0. {
+                (sectors * kernel::DISKSTATS_SECTOR_SIZE) as f32 / 1_000_000. / elapsed
+            } else {
+                0.
+            }
+        };
+        vec![
+            Reading::Count(num_reads as usize),
+            Reading::Count(num_writes as usize),
+            Reading::Level(to_mb_per_sec(sectors_read)),
+            Reading::Level(to_mb_per_sec(sectors_written)),
+        ]
+    }
+
+    fn reconfigure(&mut self, options: &SourceOptions) {
+        self.pacing = options.pacing(options.poll_intervals.disk);
+    }
+}
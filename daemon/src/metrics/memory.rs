@@ -0,0 +1,91 @@
+use std::time::{Duration, Instant};
+use sysinfo::{RefreshKind, System, SystemExt};
+use crate::kernel;
+use super::{Channel, LevelRange, MetricSource, Pacing, Reading, SourceOptions};
+
+// Heavy swapping, about 40MB a second with 4KB pages
+const SWAP_BUSY_PAGES: f32 = 10_000.;
+
+pub struct MemorySource {
+    system: System,
+    pacing: Pacing,
+    mem_usage_smooth: f32, // range [0, 1]
+    swap_usage_smooth: f32, // range [0, 1]
+    swap_activity: Option<(kernel::SwapActivity, Instant)>,
+}
+
+impl MemorySource {
+    pub fn new(pacing: Pacing) -> MemorySource {
+        MemorySource {
+            system: System::new_with_specifics(RefreshKind::new().with_memory()),
+            pacing,
+            mem_usage_smooth: 0.0,
+            swap_usage_smooth: 0.0,
+            swap_activity: kernel::read_swap_activity().map(|activity| (activity, Instant::now())),
+        }
+    }
+
+    // pages swapped in per second, pages swapped out per second
+    fn swap_rates(&mut self) -> (f32, f32) {
+        let mut swap_in_rate = 0.;
+        let mut swap_out_rate = 0.;
+        if let Some(activity) = kernel::read_swap_activity() {
+            let now = Instant::now();
+            if let Some((prev, prev_time)) = self.swap_activity {
+                let elapsed = now.duration_since(prev_time).as_secs_f32();
+                if elapsed > 0. {
+                    swap_in_rate = activity.pages_in.saturating_sub(prev.pages_in) as f32 / elapsed;
+                    swap_out_rate = activity.pages_out.saturating_sub(prev.pages_out) as f32 / elapsed;
+                }
+            }
+            self.swap_activity = Some((activity, now));
+        }
+        (swap_in_rate, swap_out_rate)
+    }
+}
+
+impl MetricSource for MemorySource {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn poll_interval(&self) -> Duration {
+        self.pacing.interval
+    }
+
+    fn channels(&self) -> Vec<Channel> {
+        vec![
+            Channel::level("mem_load"),
+            Channel::level("swap_usage"),
+            // Pages per second
+            Channel::level_in("swap_in", LevelRange::Rate { busy: SWAP_BUSY_PAGES }),
+            Channel::level_in("swap_out", LevelRange::Rate { busy: SWAP_BUSY_PAGES }),
+        ]
+    }
+
+    fn poll(&mut self) -> Vec<Reading> {
+        let system = &mut self.system;
+        system.refresh_memory();
+        let normed_mem_usage_raw = system.used_memory() as f32 / system.total_memory() as f32;
+        if normed_mem_usage_raw.is_normal() {
+            self.mem_usage_smooth = self.pacing.smear(self.mem_usage_smooth, normed_mem_usage_raw);
+        }
+        // Unlike memory, swap usage legitimately sits at exactly zero, so only guard against no swap at all
+        if system.total_swap() > 0 {
+            let normed_swap_usage_raw = system.used_swap() as f32 / system.total_swap() as f32;
+            self.swap_usage_smooth = self.pacing.smear(self.swap_usage_smooth, normed_swap_usage_raw);
+        }
+
+        let (swap_in_rate, swap_out_rate) = self.swap_rates();
+        vec![
+            Reading::Level(self.mem_usage_smooth),
+            Reading::Level(self.swap_usage_smooth),
+            Reading::Level(swap_in_rate),
+            Reading::Level(swap_out_rate),
+        ]
+    }
+
+    fn reconfigure(&mut self, options: &SourceOptions) {
+        self.pacing = options.pacing(options.poll_intervals.memory);
+    }
+}
@@ -0,0 +1,163 @@
+mod cpu;
+mod disk;
+mod memory;
+mod network;
+mod processes;
+
+use smallvec::SmallVec;
+use std::time::Duration;
+
+pub use processes::DroneMetric;
+
+// Smoothing ratios are how far a smoothed value moves towards a new reading every
+// SMEAR_INTERVAL, whatever the actual poll interval
+const SMEAR_INTERVAL: Duration = Duration::from_millis(20);
+
+// How often a source is polled and how much it smooths its levels
+#[derive(Clone, Copy, Debug)]
+pub struct Pacing {
+    pub interval: Duration,
+    pub smoothing: f32,
+}
+
+impl Pacing {
+    // Takes the same time to settle however often the source is polled
+    fn smear(&self, smooth: f32, raw: f32) -> f32 {
+        let ratio = 1. - (1. - self.smoothing).powf(self.interval.as_secs_f32() / SMEAR_INTERVAL.as_secs_f32());
+        smooth + (raw - smooth) * ratio
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PollIntervals {
+    pub cpu: Duration,
+    pub memory: Duration,
+    pub network: Duration,
+    pub disk: Duration,
+    pub processes: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct SourceOptions {
+    pub per_core: bool,
+    pub drones_by: DroneMetric,
+    // Interface names, optionally ending in * to match a prefix
+    pub net_include: Vec<String>,
+    pub net_exclude: Vec<String>,
+    pub poll_intervals: PollIntervals,
+    pub smoothing: f32,
+}
+
+impl SourceOptions {
+    fn pacing(&self, interval: Duration) -> Pacing {
+        Pacing { interval, smoothing: self.smoothing }
+    }
+}
+
+// Maps [0, max] onto [0, 1] logarithmically, for quantities that span orders of magnitude
+fn log_scale(value: f32, max: f32) -> f32 {
+    ((1. + value).ln() / (1. + max).ln()).clamp(0., 1.)
+}
+
+// What a level's readings run between, for sinks that need them in [0, 1]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LevelRange {
+    // [0, 1], like CPU usage
+    Unit,
+    // [-1, 1], left to right
+    Pan,
+    // 0 or 1, a setting rather than a measurement
+    Switch,
+    // From 0 up with no ceiling, like MB per second. `busy` is about as high as it gets on
+    // a busy machine.
+    Rate { busy: f32 },
+}
+
+impl LevelRange {
+    // Rates go onto [0, 1] logarithmically, reaching 1 at `busy`
+    pub fn normalise(&self, level: f32) -> f32 {
+        match self {
+            LevelRange::Unit | LevelRange::Switch => level.clamp(0., 1.),
+            LevelRange::Pan => ((level + 1.) / 2.).clamp(0., 1.),
+            LevelRange::Rate { busy } => log_scale(level, *busy),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ChannelKind {
+    // A continuous value, like CPU usage
+    Level { range: LevelRange },
+    // Things that happen, like a process exiting. Each event is an impulse as high as its
+    // velocity, and each of the named values (pan and so on) is held until the next event.
+    // The DSP sees them as inputs called <channel> and <channel>_<value>.
+    Events { values: &'static [&'static str] },
+}
+
+#[derive(Clone, Debug)]
+pub struct Channel {
+    pub name: String,
+    pub kind: ChannelKind,
+}
+
+impl Channel {
+    pub fn level(name: &str) -> Channel {
+        Channel::level_in(name, LevelRange::Unit)
+    }
+
+    pub fn level_in(name: &str, range: LevelRange) -> Channel {
+        Channel { name: name.to_owned(), kind: ChannelKind::Level { range } }
+    }
+
+    pub fn events(name: &str, values: &'static [&'static str]) -> Channel {
+        Channel { name: name.to_owned(), kind: ChannelKind::Events { values } }
+    }
+}
+
+#[derive(Clone)]
+pub struct Event {
+    pub velocity: f32,
+    // In the order of the channel's values
+    pub values: SmallVec<[f32; 4]>,
+}
+
+#[derive(Clone)]
+pub enum Reading {
+    Level(f32),
+    Events(Vec<Event>),
+    // Some number of velocity 1 events without values, like packets
+    Count(usize),
+}
+
+pub trait MetricSource: Send {
+    fn name(&self) -> &'static str;
+
+    // Everything this source reports, which stays the same for its whole life
+    fn channels(&self) -> Vec<Channel>;
+
+    // How long to wait between polls. Sources are polled on their own threads, so a slow
+    // source only makes its own readings late.
+    fn poll_interval(&self) -> Duration;
+
+    // One reading per channel, in the same order as channels(). Level readings are the
+    // latest value, events and counts are everything since the last poll.
+    fn poll(&mut self) -> Vec<Reading>;
+
+    // Picks up new options without losing any state, called between polls
+    fn reconfigure(&mut self, options: &SourceOptions);
+}
+
+pub fn register_sources(options: SourceOptions) -> Vec<Box<dyn MetricSource>> {
+    let intervals = &options.poll_intervals;
+    vec![
+        Box::new(cpu::CpuSource::new(options.pacing(intervals.cpu), options.per_core)),
+        Box::new(memory::MemorySource::new(options.pacing(intervals.memory))),
+        Box::new(network::NetworkSource::new(
+            options.pacing(intervals.network),
+            options.net_include.clone(),
+            options.net_exclude.clone(),
+        )),
+        Box::new(disk::DiskSource::new(options.pacing(intervals.disk))),
+        Box::new(processes::ProcessSource::new(options.pacing(intervals.processes), options.drones_by)),
+    ]
+}
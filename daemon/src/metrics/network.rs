@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use sysinfo::{NetworkExt, RefreshKind, System, SystemExt};
+use crate::kernel;
+use super::{Channel, MetricSource, Pacing, Reading, SourceOptions};
+
+// How often to look for interfaces that came or went
+const LIST_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+fn interface_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+pub struct NetworkSource {
+    system: System,
+    pacing: Pacing,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    interface_kinds: HashMap<String, kernel::InterfaceKind>,
+    list_refreshed: Instant,
+}
+
+impl NetworkSource {
+    pub fn new(pacing: Pacing, include: Vec<String>, exclude: Vec<String>) -> NetworkSource {
+        NetworkSource {
+            system: System::new_with_specifics(RefreshKind::new().with_networks().with_networks_list()),
+            pacing,
+            include,
+            exclude,
+            interface_kinds: HashMap::new(),
+            list_refreshed: Instant::now(),
+        }
+    }
+
+    fn is_interface_included(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|pattern| interface_matches(pattern, name)))
+            && !self.exclude.iter().any(|pattern| interface_matches(pattern, name))
+    }
+}
+
+impl MetricSource for NetworkSource {
+    fn name(&self) -> &'static str {
+        "network"
+    }
+
+    fn poll_interval(&self) -> Duration {
+        self.pacing.interval
+    }
+
+    // Loopback and virtual interfaces count as internal
+    fn channels(&self) -> Vec<Channel> {
+        vec![
+            Channel::events("packets_in", &[]),
+            Channel::events("packets_out", &[]),
+            Channel::events("internal_packets_in", &[]),
+            Channel::events("internal_packets_out", &[]),
+        ]
+    }
+
+    fn poll(&mut self) -> Vec<Reading> {
+        // Refreshing the list also refreshes the counters of interfaces it keeps
+        if self.list_refreshed.elapsed() >= LIST_REFRESH_INTERVAL {
+            self.system.refresh_networks_list();
+            self.list_refreshed = Instant::now();
+            // A name that comes back may be a different kind of interface
+            let networks = self.system.networks();
+            self.interface_kinds
+                .retain(|name, _| networks.into_iter().any(|(present, _)| present == name));
+        } else {
+            self.system.refresh_networks();
+        }
+        let mut num_inc_packets = 0;
+        let mut num_out_packets = 0;
+        let mut num_internal_inc_packets = 0;
+        let mut num_internal_out_packets = 0;
+        for (name, data) in self.system.networks() {
+            if !self.is_interface_included(name) {
+                continue;
+            }
+            let kind = *self
+                .interface_kinds
+                .entry(name.clone())
+                .or_insert_with(|| kernel::classify_interface(name));
+            if kind == kernel::InterfaceKind::Physical {
+                num_inc_packets += data.packets_received() as usize;
+                num_out_packets += data.packets_transmitted() as usize;
+            } else {
+                num_internal_inc_packets += data.packets_received() as usize;
+                num_internal_out_packets += data.packets_transmitted() as usize;
+            }
+        };
+        vec![
+            Reading::Count(num_inc_packets),
+            Reading::Count(num_out_packets),
+            Reading::Count(num_internal_inc_packets),
+            Reading::Count(num_internal_out_packets),
+        ]
+    }
+
+    fn reconfigure(&mut self, options: &SourceOptions) {
+        self.pacing = options.pacing(options.poll_intervals.network);
+        self.include = options.net_include.clone();
+        self.exclude = options.net_exclude.clone();
+    }
+}
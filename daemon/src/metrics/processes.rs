@@ -0,0 +1,265 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use smallvec::smallvec;
+use sysinfo::{PidExt, ProcessExt, ProcessRefreshKind, RefreshKind, System, SystemExt};
+use serde::Deserialize;
+use super::{log_scale, Channel, Event, LevelRange, MetricSource, Pacing, Reading, SourceOptions};
+
+const NUM_DRONES: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DroneMetric {
+    #[default]
+    Cpu,
+    Memory,
+}
+
+// FNV-1a, so placement doesn't change between runs or Rust versions
+fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Where in the stereo field a program lives, from its executable path (or its name for
+// kernel threads and the like, which have no executable)
+fn executable_pan(process: &sysinfo::Process) -> f32 {
+    let exe = process.exe();
+    let identity = if exe.as_os_str().is_empty() {
+        process.name().to_owned()
+    } else {
+        exe.to_string_lossy().into_owned()
+    };
+    identity_pan(&identity)
+}
+
+fn identity_pan(identity: &str) -> f32 {
+    (stable_hash(identity.as_bytes()) % 1024) as f32 / 511.5 - 1.
+}
+
+// Instances of the same program cluster around where it lives, offset a little by PID
+fn process_pan(process: &sysinfo::Process) -> f32 {
+    jittered_pan(executable_pan(process), process.pid().as_u32())
+}
+
+fn jittered_pan(pan: f32, pid: u32) -> f32 {
+    let pid_jitter = (stable_hash(&pid.to_le_bytes()) % 256) as f32 / 1275. - 0.1;
+    (pan + pid_jitter).clamp(-1., 1.)
+}
+
+// What we remember about a process, for when it's gone
+struct SeenProcess {
+    pub pan: f32, // range [-1, 1]
+    pub memory: u64, // KB
+    pub run_time: u64, // seconds
+    // Only counted since we first saw the process
+    pub cpu_seconds: f32,
+}
+
+impl SeenProcess {
+    // A 64 GB process has size 1, a day old process age 1 and an hour of CPU time work 1.
+    fn exit_event(&self) -> Event {
+        let size = log_scale(self.memory as f32 / 1024., 65536.);
+        let age = log_scale(self.run_time as f32, 86400.);
+        let work = log_scale(self.cpu_seconds, 3600.);
+        Event {
+            velocity: 0.4 + 0.6 * size,
+            values: smallvec![self.pan, size, age, work],
+        }
+    }
+}
+
+// Pass in what we saw last time and the seconds since, to keep adding up CPU time
+fn get_seen_processes(
+    sys: &System,
+    prev_seen: &HashMap<sysinfo::Pid, SeenProcess>,
+    elapsed: f32,
+) -> HashMap<sysinfo::Pid, SeenProcess> {
+    // This doesn't refresh the system.processes buffer
+    sys.processes()
+        .iter()
+        .map(|(pid, process)| {
+            let prev_cpu_seconds = prev_seen.get(pid).map(|seen| seen.cpu_seconds).unwrap_or(0.);
+            let cpu_seconds = prev_cpu_seconds + process.cpu_usage() / 100. * elapsed;
+            (*pid, SeenProcess {
+                pan: process_pan(process),
+                memory: process.memory(),
+                run_time: process.run_time(),
+                cpu_seconds: if cpu_seconds.is_finite() { cpu_seconds } else { prev_cpu_seconds },
+            })
+        })
+        .collect()
+}
+
+struct Drone {
+    pub pid: sysinfo::Pid,
+    pub pan: f32, // range [-1, 1]
+    pub level_smooth: f32, // range [0, 1]
+    // Still in the top N; otherwise the drone is fading out before its slot is reused
+    pub active: bool,
+}
+
+pub struct ProcessSource {
+    system: System,
+    pacing: Pacing,
+    drones_by: DroneMetric,
+    seen_processes: HashMap<sysinfo::Pid, SeenProcess>,
+    seen_processes_time: Instant,
+    drones: [Option<Drone>; NUM_DRONES],
+}
+
+impl ProcessSource {
+    pub fn new(pacing: Pacing, drones_by: DroneMetric) -> ProcessSource {
+        let mut system = System::new_with_specifics(
+            RefreshKind::new()
+                .with_memory()
+                .with_processes(ProcessRefreshKind::new().with_cpu()),
+        );
+        system.refresh_processes();
+        ProcessSource {
+            pacing,
+            drones_by,
+            seen_processes: get_seen_processes(&system, &HashMap::new(), 0.),
+            seen_processes_time: Instant::now(),
+            drones: Default::default(),
+            system,
+        }
+    }
+
+    fn drone_usage(&self, process: &sysinfo::Process) -> f32 {
+        match self.drones_by {
+            // One pegged core counts as full usage
+            DroneMetric::Cpu => (process.cpu_usage() / 100.0).min(1.),
+            DroneMetric::Memory => process.memory() as f32 / self.system.total_memory() as f32,
+        }
+    }
+
+    // A process keeps its slot for as long as it stays in the top N, so voices don't jump around.
+    fn update_drones(&mut self) {
+        let mut top_processes = self
+            .system
+            .processes()
+            .values()
+            .map(|process| (process.pid(), self.drone_usage(process), executable_pan(process)))
+            .filter(|(_, usage, _)| usage.is_finite())
+            .collect::<Vec<(sysinfo::Pid, f32, f32)>>();
+        top_processes.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        top_processes.truncate(NUM_DRONES);
+
+        for (pid, _, pan) in top_processes.iter() {
+            if self.drones.iter().flatten().any(|drone| drone.pid == *pid) {
+                continue;
+            }
+            let free_slot = self.drones.iter_mut().find(|slot| match slot {
+                None => true,
+                Some(drone) => !drone.active && drone.level_smooth < 0.01,
+            });
+            if let Some(slot) = free_slot {
+                *slot = Some(Drone { pid: *pid, pan: *pan, level_smooth: 0., active: true });
+            }
+        }
+
+        for drone in self.drones.iter_mut().flatten() {
+            let usage = top_processes.iter().find(|(pid, _, _)| *pid == drone.pid).map(|(_, usage, _)| *usage);
+            drone.active = usage.is_some();
+            drone.level_smooth = self.pacing.smear(drone.level_smooth, usage.unwrap_or(0.));
+        }
+    }
+}
+
+impl MetricSource for ProcessSource {
+    fn name(&self) -> &'static str {
+        "processes"
+    }
+
+    fn poll_interval(&self) -> Duration {
+        self.pacing.interval
+    }
+
+    // spawns and exits, then drone_<n> and drone_<n>_pan for each drone
+    fn channels(&self) -> Vec<Channel> {
+        let mut channels = vec![
+            Channel::events("process_spawn", &["pan"]),
+            Channel::events("process_exit", &["pan", "size", "age", "work"]),
+        ];
+        channels.extend((0..NUM_DRONES).map(|drone| Channel::level(&format!("drone_{}", drone))));
+        channels.extend((0..NUM_DRONES).map(|drone| Channel::level_in(&format!("drone_{}_pan", drone), LevelRange::Pan)));
+        channels
+    }
+
+    fn poll(&mut self) -> Vec<Reading> {
+        self.system.refresh_memory();
+        self.system.refresh_processes();
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.seen_processes_time).as_secs_f32();
+        let processes = self.system.processes();
+
+        let spawned_events = processes
+            .iter()
+            .filter(|(pid, _)| !self.seen_processes.contains_key(pid))
+            .map(|(_, process)| Event { velocity: 1., values: smallvec![process_pan(process)] })
+            .collect::<Vec<Event>>();
+        let dropped_events = self
+            .seen_processes
+            .iter()
+            .filter(|(pid, _)| !processes.contains_key(pid))
+            .map(|(_, seen)| seen.exit_event())
+            .collect::<Vec<Event>>();
+
+        // Re-read every time, since a freshly forked process only gets its real executable on exec
+        self.seen_processes = get_seen_processes(&self.system, &self.seen_processes, elapsed);
+        self.seen_processes_time = now;
+        self.update_drones();
+
+        let mut readings = vec![Reading::Events(spawned_events), Reading::Events(dropped_events)];
+        readings.extend(self.drones.iter().map(|slot| {
+            Reading::Level(slot.as_ref().map(|drone| drone.level_smooth).unwrap_or(0.))
+        }));
+        readings.extend(self.drones.iter().map(|slot| {
+            Reading::Level(slot.as_ref().map(|drone| drone.pan).unwrap_or(0.))
+        }));
+        readings
+    }
+
+    fn reconfigure(&mut self, options: &SourceOptions) {
+        self.pacing = options.pacing(options.poll_intervals.processes);
+        self.drones_by = options.drones_by;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_is_fnv1a() {
+        assert_eq!(stable_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(stable_hash(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn pan_is_stable() {
+        let pan = identity_pan("/usr/bin/firefox");
+        assert_eq!(identity_pan("/usr/bin/firefox"), pan);
+        // Pinned, so a change that would move programs around between runs shows up here
+        assert_eq!(pan, 344. / 511.5 - 1.);
+        assert_eq!(jittered_pan(pan, 1234), jittered_pan(identity_pan("/usr/bin/firefox"), 1234));
+    }
+
+    #[test]
+    fn pan_stays_in_range() {
+        for i in 0..10_000u32 {
+            let pan = identity_pan(&format!("/usr/bin/program-{}", i));
+            assert!((-1. ..=1.).contains(&pan), "{} out of range", pan);
+            for pan in [jittered_pan(pan, i), jittered_pan(-1., i), jittered_pan(1., i)] {
+                assert!((-1. ..=1.).contains(&pan), "{} out of range", pan);
+            }
+        }
+    }
+}
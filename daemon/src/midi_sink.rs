@@ -0,0 +1,527 @@
+// Records a session's metrics as a Standard MIDI File, to re-orchestrate or pick through
+// an incident in any MIDI tool afterwards. It's a single track file with each source on
+// its own MIDI channel, and one tick is one millisecond. On each channel:
+//   events like process_exit are notes from key 60 up, velocity from the event's velocity,
+//       with the event's pan sent as CC 10 and its other values as CCs just before the note
+//   counts like packets_in are a note from key 60 up for each poll with any, louder the
+//       more there were
+//   levels like cpu_load are CCs, plus a note from key 36 up held for as long as the
+//       level is over the threshold. Rates like disk_read_mb are scaled onto the CC's
+//       range logarithmically first. Pans and switches like per_core_mode are only CCs.
+// When a source first shows up, text events list which of its metrics is on which key or
+// CC. Events are appended to the file every few seconds and when the daemon stops, so a
+// crash only loses the last few seconds, and a long session doesn't pile up in memory.
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use crate::metrics::{ChannelKind, LevelRange, Reading};
+use crate::patchbay::{MetricTap, Snapshot};
+
+const TAP_CAPACITY: usize = 256;
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// 500 ticks a beat at 120 beats a minute is a tick a millisecond
+const TICKS_PER_BEAT: u16 = 500;
+const MICROS_PER_BEAT: u32 = 500_000;
+// About three days, longer gaps are shortened
+const MAX_DELTA: u64 = (1 << 28) - 1;
+// Where the track's length goes, after the 14 byte header and the track's "MTrk"
+const TRACK_LENGTH_AT: u64 = 18;
+const END_OF_TRACK: [u8; 4] = [0, 0xff, 0x2f, 0];
+
+const LEVEL_KEYS_FROM: u8 = 36;
+const EVENT_KEYS_FROM: u8 = 60;
+const PAN_CC: u8 = 10;
+// Controllers General MIDI leaves undefined, handed out in order
+const FREE_CCS: [std::ops::Range<u8>; 2] = [20..32, 102..120];
+// Notes for events are this long, or shorter when they come faster
+const NOTE_MS: u64 = 100;
+// A level has to fall this far back under the threshold to end its note, so one hovering
+// around the threshold doesn't stutter
+const HYSTERESIS: f32 = 0.05;
+
+fn seven_bit(value: f32) -> u8 {
+    (value.clamp(0., 1.) * 127.).round() as u8
+}
+
+// Notes need a velocity of at least 1, 0 means note off
+fn note_velocity(velocity: f32) -> u8 {
+    (velocity.clamp(0., 1.) * 127.).round().max(1.) as u8
+}
+
+fn count_velocity(num: usize) -> u8 {
+    ((num as f32 + 1.).log2() * 16.).clamp(1., 127.) as u8
+}
+
+// MIDI's variable length numbers, 7 bits a byte with the high bit on all but the last
+fn write_varlen(value: u64, out: &mut Vec<u8>) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        bytes.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+enum Message {
+    NoteOn { channel: u8, key: u8, vel: u8 },
+    NoteOff { channel: u8, key: u8 },
+    Controller { channel: u8, cc: u8, value: u8 },
+    Text(String),
+}
+
+impl Message {
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Message::NoteOn { channel, key, vel } => out.extend([0x90 | channel, *key, *vel]),
+            Message::NoteOff { channel, key } => out.extend([0x80 | channel, *key, 0]),
+            Message::Controller { channel, cc, value } => out.extend([0xb0 | channel, *cc, *value]),
+            Message::Text(text) => {
+                out.extend([0xff, 0x01]);
+                write_varlen(text.len() as u64, out);
+                out.extend(text.as_bytes());
+            }
+        }
+    }
+}
+
+// A single track Standard MIDI File that only grows at the end. The end of the track and
+// its length are rewritten after every append, so the file is whole between appends.
+struct MidiFile {
+    path: PathBuf,
+    file: File,
+    track_length: u32,
+}
+
+impl MidiFile {
+    fn create(path: &Path) -> Result<MidiFile> {
+        let file = File::create(path).with_context(|| format!("Couldn't create {}", path.display()))?;
+        let mut header = b"MThd".to_vec();
+        header.extend(6u32.to_be_bytes());
+        // Format 0, one track
+        header.extend(0u16.to_be_bytes());
+        header.extend(1u16.to_be_bytes());
+        header.extend(TICKS_PER_BEAT.to_be_bytes());
+        header.extend(b"MTrk");
+        header.extend(0u32.to_be_bytes());
+        let mut midi_file = MidiFile { path: path.to_owned(), file, track_length: 0 };
+        midi_file.file.write_all(&header).with_context(|| format!("Couldn't write {}", path.display()))?;
+
+        let mut first = Vec::new();
+        // Track name, then tempo
+        first.extend([0, 0xff, 0x03, 7]);
+        first.extend(b"audimon");
+        first.extend([0, 0xff, 0x51, 3]);
+        first.extend(&MICROS_PER_BEAT.to_be_bytes()[1..]);
+        first.extend(END_OF_TRACK);
+        midi_file.file.write_all(&first).with_context(|| format!("Couldn't write {}", path.display()))?;
+        midi_file.track_length = first.len() as u32;
+        midi_file.write_track_length()?;
+        Ok(midi_file)
+    }
+
+    fn append(&mut self, events: &[u8]) -> Result<()> {
+        let track_length = self.track_length + events.len() as u32;
+        let appended = (|| -> std::io::Result<()> {
+            // Over the old end of the track
+            self.file.seek(SeekFrom::End(-(END_OF_TRACK.len() as i64)))?;
+            self.file.write_all(events)?;
+            self.file.write_all(&END_OF_TRACK)?;
+            self.file.seek(SeekFrom::Start(TRACK_LENGTH_AT))?;
+            self.file.write_all(&track_length.to_be_bytes())
+        })();
+        if appended.is_err() {
+            // Whatever part of it made it in would leave the track's length wrong
+            let _ = self.roll_back();
+        }
+        appended.with_context(|| format!("Couldn't write {}", self.path.display()))?;
+        self.track_length = track_length;
+        Ok(())
+    }
+
+    // Cuts the file back to how it was after the last whole append
+    fn roll_back(&mut self) -> std::io::Result<()> {
+        let end = TRACK_LENGTH_AT + 4 + self.track_length as u64;
+        self.file.set_len(end - END_OF_TRACK.len() as u64)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&END_OF_TRACK)?;
+        self.file.seek(SeekFrom::Start(TRACK_LENGTH_AT))?;
+        self.file.write_all(&self.track_length.to_be_bytes())
+    }
+
+    fn write_track_length(&mut self) -> Result<()> {
+        let written = (|| -> std::io::Result<()> {
+            self.file.seek(SeekFrom::Start(TRACK_LENGTH_AT))?;
+            self.file.write_all(&self.track_length.to_be_bytes())
+        })();
+        written.with_context(|| format!("Couldn't write {}", self.path.display()))
+    }
+}
+
+// Where each of a source's channels goes
+enum Mapping {
+    // No key for levels that never hold a note
+    Level { range: LevelRange, cc: Option<u8>, key: Option<u8> },
+    Events { key: u8, value_ccs: Vec<Option<u8>> },
+}
+
+struct SourceTrack {
+    name: &'static str,
+    channel: u8,
+    mappings: Vec<Mapping>,
+    last_ccs: Vec<Option<u8>>,
+    over_threshold: Vec<bool>,
+}
+
+impl SourceTrack {
+    // Returns the source's legend as well, to go in the file as text events
+    fn new(snapshot: &Snapshot, index: usize) -> (SourceTrack, Vec<String>) {
+        // There are only 16, more sources than that share
+        let channel = (index % 16) as u8;
+        let mut free_ccs = FREE_CCS.iter().cloned().flatten();
+        let mut legend = vec![format!("{} is on channel {}", snapshot.source, channel + 1)];
+        let mut level_keys = LEVEL_KEYS_FROM..EVENT_KEYS_FROM;
+        let mut event_keys = EVENT_KEYS_FROM..128;
+        let mappings = snapshot
+            .channels
+            .iter()
+            .map(|metric| match &metric.kind {
+                ChannelKind::Level { range } => {
+                    let cc = free_ccs.next();
+                    if let Some(cc) = cc {
+                        legend.push(format!("{} cc {}: {}", snapshot.source, cc, metric.name));
+                    }
+                    let key = match range {
+                        LevelRange::Unit | LevelRange::Rate { .. } => {
+                            let key = level_keys.next().expect("Too many levels to give each a key");
+                            legend.push(format!("{} key {}: {} over the threshold", snapshot.source, key, metric.name));
+                            Some(key)
+                        }
+                        LevelRange::Pan | LevelRange::Switch => None,
+                    };
+                    Mapping::Level { range: *range, cc, key }
+                }
+                ChannelKind::Events { values } => {
+                    let key = event_keys.next().expect("Too many event channels to give each a key");
+                    legend.push(format!("{} key {}: {}", snapshot.source, key, metric.name));
+                    let value_ccs = values
+                        .iter()
+                        .map(|value| {
+                            let cc = if *value == "pan" { Some(PAN_CC) } else { free_ccs.next() };
+                            if let Some(cc) = cc {
+                                legend.push(format!("{} cc {}: {} {}", snapshot.source, cc, metric.name, value));
+                            }
+                            cc
+                        })
+                        .collect();
+                    Mapping::Events { key, value_ccs }
+                }
+            })
+            .collect();
+        let track = SourceTrack {
+            name: snapshot.source,
+            channel,
+            mappings,
+            last_ccs: snapshot.channels.iter().map(|_| None).collect(),
+            over_threshold: snapshot.channels.iter().map(|_| false).collect(),
+        };
+        (track, legend)
+    }
+
+    // Messages at absolute times in milliseconds
+    fn record(&mut self, snapshot: Snapshot, at: u64, threshold: f32, messages: &mut Vec<(u64, Message)>) {
+        let channel = self.channel;
+        let interval = snapshot.poll_interval.as_millis() as u64;
+        for (index, reading) in snapshot.readings.into_iter().enumerate() {
+            match (&self.mappings[index], reading) {
+                (Mapping::Level { range, cc, key }, Reading::Level(level)) => {
+                    let level = range.normalise(level);
+                    if let Some(cc) = cc {
+                        let value = seven_bit(level);
+                        // Only changes, or a slow level would fill the file with repeats
+                        if self.last_ccs[index] != Some(value) {
+                            self.last_ccs[index] = Some(value);
+                            messages.push((at, Message::Controller { channel, cc: *cc, value }));
+                        }
+                    }
+                    if let Some(key) = key {
+                        let key = *key;
+                        if !self.over_threshold[index] && level >= threshold {
+                            self.over_threshold[index] = true;
+                            messages.push((at, Message::NoteOn { channel, key, vel: note_velocity(level) }));
+                        } else if self.over_threshold[index] && level < threshold - HYSTERESIS {
+                            self.over_threshold[index] = false;
+                            messages.push((at, Message::NoteOff { channel, key }));
+                        }
+                    }
+                }
+                (Mapping::Events { key, value_ccs }, Reading::Events(events)) => {
+                    // A poll's events all happened some time since the last one, so spread
+                    // them over it like the patchbay does
+                    let spacing = interval / events.len().max(1) as u64;
+                    let length = NOTE_MS.min(spacing / 2).max(1);
+                    let from = at.saturating_sub(interval);
+                    for (num, event) in events.into_iter().enumerate() {
+                        let start = from + spacing * num as u64;
+                        for (cc, value) in value_ccs.iter().zip(event.values.iter()) {
+                            if let Some(cc) = cc {
+                                let range = if *cc == PAN_CC { LevelRange::Pan } else { LevelRange::Unit };
+                                let value = seven_bit(range.normalise(*value));
+                                messages.push((start, Message::Controller { channel, cc: *cc, value }));
+                            }
+                        }
+                        let key = *key;
+                        messages.push((start, Message::NoteOn { channel, key, vel: note_velocity(event.velocity) }));
+                        messages.push((start + length, Message::NoteOff { channel, key }));
+                    }
+                }
+                (Mapping::Events { key, .. }, Reading::Count(num)) if num > 0 => {
+                    let key = *key;
+                    let length = NOTE_MS.min(interval / 2).max(1);
+                    messages.push((at, Message::NoteOn { channel, key, vel: count_velocity(num) }));
+                    messages.push((at + length, Message::NoteOff { channel, key }));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Ends the notes of levels still over the threshold, since the file has to make sense
+    // even though the session hasn't
+    fn held_notes_off(&self, at: u64) -> Vec<(u64, Message)> {
+        self.mappings
+            .iter()
+            .zip(self.over_threshold.iter())
+            .filter_map(|(mapping, over)| match mapping {
+                Mapping::Level { key: Some(key), .. } if *over => {
+                    Some((at, Message::NoteOff { channel: self.channel, key: *key }))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+struct Recording {
+    started: Instant,
+    threshold: f32,
+    tracks: Vec<SourceTrack>,
+    // Not yet in the file. Spread events are backdated by up to a poll interval, so
+    // messages wait until nothing recorded later could land before them.
+    pending: Vec<(u64, Message)>,
+    longest_poll: u64,
+    // When the file's last event is
+    written_until: u64,
+}
+
+impl Recording {
+    fn record(&mut self, snapshot: Snapshot) {
+        let at = snapshot.taken.saturating_duration_since(self.started).as_millis() as u64;
+        self.longest_poll = self.longest_poll.max(snapshot.poll_interval.as_millis() as u64);
+        let index = match self.tracks.iter().position(|track| track.name == snapshot.source) {
+            Some(index) => index,
+            None => {
+                let (track, legend) = SourceTrack::new(&snapshot, self.tracks.len());
+                self.pending.extend(legend.into_iter().map(|line| (at, Message::Text(line))));
+                self.tracks.push(track);
+                self.tracks.len() - 1
+            }
+        };
+        self.tracks[index].record(snapshot, at, self.threshold, &mut self.pending);
+    }
+
+    // The encoded events that are ready to go in the file, or all of them at the end
+    fn take_ready(&mut self, end: bool) -> Vec<u8> {
+        let now = self.started.elapsed().as_millis() as u64;
+        if end {
+            for track in self.tracks.iter() {
+                self.pending.extend(track.held_notes_off(now));
+            }
+        }
+        let cutoff = if end { u64::MAX } else { now.saturating_sub(self.longest_poll) };
+        self.pending.sort_by_key(|(at, _)| *at);
+        let ready = self.pending.partition_point(|(at, _)| *at < cutoff);
+        let mut events = Vec::new();
+        for (at, message) in self.pending.drain(..ready) {
+            // Polls made longer since can still reach back before what's written
+            let at = at.max(self.written_until);
+            write_varlen((at - self.written_until).min(MAX_DELTA), &mut events);
+            message.write(&mut events);
+            self.written_until = at;
+        }
+        events
+    }
+}
+
+// File writes are blocking, so they're done off the async threads
+async fn append(mut file: MidiFile, events: Vec<u8>) -> Result<(MidiFile, Result<()>)> {
+    Ok(tokio::task::spawn_blocking(move || {
+        let appended = file.append(&events);
+        (file, appended)
+    })
+    .await?)
+}
+
+// Returns the tap to hand to the pollers, and the recording task, which saves one last
+// time once `shutdown` goes true, or stops as soon as saving fails
+pub fn midi_sink(path: PathBuf, threshold: f32, mut shutdown: watch::Receiver<bool>) -> Result<(MetricTap, JoinHandle<Result<()>>)> {
+    // Finds out now rather than at the first save if the file can't be written
+    let mut file = MidiFile::create(&path)?;
+    println!("Recording metrics as MIDI to {}", path.display());
+    let mut recording = Recording {
+        started: Instant::now(),
+        threshold,
+        tracks: Vec::new(),
+        pending: Vec::new(),
+        longest_poll: 0,
+        written_until: 0,
+    };
+    let (tap, mut snapshots) = mpsc::channel::<Snapshot>(TAP_CAPACITY);
+    let task = tokio::spawn(async move {
+        let mut saves = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            tokio::select! {
+                Some(snapshot) = snapshots.recv() => recording.record(snapshot),
+                _ = saves.tick() => {
+                    let events = recording.take_ready(false);
+                    if events.is_empty() {
+                        continue;
+                    }
+                    let (returned, appended) = append(file, events).await?;
+                    file = returned;
+                    if let Err(err) = appended {
+                        // Like a full disk. What's already saved stays a whole file.
+                        println!("{:#}, stopped recording MIDI", err);
+                        return Ok(());
+                    }
+                }
+                _ = shutdown.changed() => break,
+            }
+        }
+        let (_, appended) = append(file, recording.take_ready(true)).await?;
+        appended?;
+        println!("Saved MIDI recording to {}", path.display());
+        Ok(())
+    });
+    Ok((tap, task))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{Channel, Event};
+    use midly::{Format, MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    fn snapshot(started: Instant, at_ms: u64, readings: Vec<Reading>) -> Snapshot {
+        Snapshot {
+            source: "test",
+            channels: Arc::new(vec![Channel::level("load"), Channel::events("exit", &["pan"])]),
+            readings,
+            taken: started + Duration::from_millis(at_ms),
+            poll_interval: Duration::from_millis(100),
+        }
+    }
+
+    // A second's worth of readings, so they're all ready to be written
+    fn recording() -> Recording {
+        let started = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
+        let mut recording = Recording {
+            started,
+            threshold: 0.5,
+            tracks: Vec::new(),
+            pending: Vec::new(),
+            longest_poll: 0,
+            written_until: 0,
+        };
+        recording.record(snapshot(started, 100, vec![Reading::Level(0.2), Reading::Events(vec![])]));
+        let exit = Event { velocity: 1., values: smallvec![-1.] };
+        recording.record(snapshot(started, 200, vec![Reading::Level(0.9), Reading::Events(vec![exit])]));
+        recording
+    }
+
+    fn track_length_is_right(bytes: &[u8]) {
+        let at = TRACK_LENGTH_AT as usize;
+        let track_length = u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        assert_eq!(bytes.len(), at + 4 + track_length as usize);
+    }
+
+    #[test]
+    fn written_files_parse() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.mid");
+        let mut file = MidiFile::create(&path).unwrap();
+        let mut recording = recording();
+
+        file.append(&recording.take_ready(false)).unwrap();
+        // Whole between appends
+        track_length_is_right(&std::fs::read(&path).unwrap());
+        Smf::parse(&std::fs::read(&path).unwrap()).unwrap();
+        file.append(&recording.take_ready(true)).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        track_length_is_right(&bytes);
+        let smf = Smf::parse(&bytes).unwrap();
+        assert_eq!(smf.header.format, Format::SingleTrack);
+        assert_eq!(smf.header.timing, Timing::Metrical(TICKS_PER_BEAT.into()));
+        assert_eq!(smf.tracks.len(), 1);
+        let track = &smf.tracks[0];
+
+        let notes_on = track
+            .iter()
+            .filter(|event| matches!(event.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. }))
+            .count();
+        let notes_off = track
+            .iter()
+            .filter(|event| matches!(event.kind, TrackEventKind::Midi { message: MidiMessage::NoteOff { .. }, .. }))
+            .count();
+        // The exit, and the level going over the threshold, ended when the recording is
+        assert_eq!((notes_on, notes_off), (2, 2));
+        let pan = track.iter().any(|event| {
+            matches!(
+                event.kind,
+                TrackEventKind::Midi { message: MidiMessage::Controller { controller, value }, .. }
+                    if controller.as_int() == PAN_CC && value.as_int() == 0
+            )
+        });
+        assert!(pan);
+        assert!(track.iter().any(|event| matches!(event.kind, TrackEventKind::Meta(MetaMessage::Text(_)))));
+        assert!(matches!(track.last().unwrap().kind, TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+        let ticks = track.iter().map(|event| event.delta.as_int() as u64).sum::<u64>();
+        assert_eq!(ticks, recording.written_until);
+    }
+
+    #[test]
+    fn partly_written_appends_are_cut_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.mid");
+        let mut file = MidiFile::create(&path).unwrap();
+        file.append(&recording().take_ready(true)).unwrap();
+        let saved = std::fs::read(&path).unwrap();
+
+        // What a write that failed partway leaves behind
+        file.file.seek(SeekFrom::End(-(END_OF_TRACK.len() as i64))).unwrap();
+        file.file.write_all(&[0, 0x90, 60]).unwrap();
+        file.roll_back().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), saved);
+    }
+
+    #[test]
+    fn varlens_are_big_endian_sevens() {
+        let varlen = |value| {
+            let mut out = Vec::new();
+            write_varlen(value, &mut out);
+            out
+        };
+        assert_eq!(varlen(0), vec![0]);
+        assert_eq!(varlen(0x7f), vec![0x7f]);
+        assert_eq!(varlen(0x80), vec![0x81, 0]);
+        assert_eq!(varlen(MAX_DELTA), vec![0xff, 0xff, 0xff, 0x7f]);
+    }
+}
@@ -0,0 +1,52 @@
+// Opus encoding of rendered frames, shared by the sinks that send or store compressed audio
+use anyhow::Result;
+use audiopus::coder::Encoder;
+use crate::audio::AudioConfig;
+
+// Big enough for any single Opus packet
+const MAX_PACKET_SIZE: usize = 4096;
+
+fn opus_sample_rate(sample_rate: u32) -> Result<audiopus::SampleRate> {
+    Ok(match sample_rate {
+        8000 => audiopus::SampleRate::Hz8000,
+        12000 => audiopus::SampleRate::Hz12000,
+        16000 => audiopus::SampleRate::Hz16000,
+        24000 => audiopus::SampleRate::Hz24000,
+        48000 => audiopus::SampleRate::Hz48000,
+        _ => anyhow::bail!("Opus can't encode {} Hz", sample_rate),
+    })
+}
+
+pub struct OpusEncoder {
+    encoder: Encoder,
+    // Interleaved left and right
+    in_buffer: Vec<i16>,
+    out_buffer: Vec<u8>,
+}
+
+impl OpusEncoder {
+    pub fn new(audio_config: AudioConfig) -> Result<OpusEncoder> {
+        audio_config.check_opus()?;
+        let mut encoder = Encoder::new(
+            opus_sample_rate(audio_config.sample_rate)?,
+            audiopus::Channels::Stereo,
+            audiopus::Application::Audio,
+        )?;
+        encoder.set_complexity(8)?;
+        Ok(OpusEncoder {
+            encoder,
+            in_buffer: vec![0; audio_config.frame_size() * 2],
+            out_buffer: vec![0; MAX_PACKET_SIZE],
+        })
+    }
+
+    // One frame in, one packet out
+    pub fn encode(&mut self, frame: &[(i16, i16)]) -> Result<&[u8]> {
+        for (i, (left, right)) in frame.iter().enumerate() {
+            self.in_buffer[i * 2] = *left;
+            self.in_buffer[i * 2 + 1] = *right;
+        }
+        let size = self.encoder.encode(&self.in_buffer, &mut self.out_buffer)?;
+        Ok(&self.out_buffer[..size])
+    }
+}
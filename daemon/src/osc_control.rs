@@ -0,0 +1,144 @@
+// An OSC server over UDP, so TouchOSC, scripts or other audio tools can drive the daemon.
+//   /audimon/volume <db>
+//   /audimon/layer/<layer>/gain <db>       layers: memory processes cpu packets drones swap disk
+//   /audimon/layer/<layer>/mute <0|1>
+//   /audimon/param/<name or path> <value>  any DSP parameter, see `ctl list`
+//   /audimon/smoothing <ratio>
+//   /audimon/per_core <0|1>
+//   /audimon/drones_by <"cpu"|"memory">
+//   /audimon/poll_ms/<source> <ms>         sources: cpu memory network disk processes
+// Numbers can be sent as any OSC int, float, double or bool.
+use anyhow::{bail, Result};
+use rosc::{OscMessage, OscPacket, OscType};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use crate::audio::{self, DspState};
+use crate::metrics::{DroneMetric, SourceOptions};
+
+const POLLED_SOURCES: [&str; 5] = ["cpu", "memory", "network", "disk", "processes"];
+
+fn as_number(arg: &OscType) -> Option<f32> {
+    match arg {
+        OscType::Float(value) => Some(*value),
+        OscType::Double(value) => Some(*value as f32),
+        OscType::Int(value) => Some(*value as f32),
+        OscType::Long(value) => Some(*value as f32),
+        OscType::Bool(value) => Some(if *value { 1. } else { 0. }),
+        _ => None,
+    }
+}
+
+fn set_dsp_param(dsp_state: &Option<DspState>, name: &str, value: f32) -> Result<()> {
+    let mut state = audio::lock_dsp(dsp_state)?;
+    audio::set_dsp_params(&mut state, &BTreeMap::from([(name.to_owned(), value)]))
+}
+
+// send_modify would do this in place, but needs a newer tokio
+fn change_options(options: &watch::Sender<SourceOptions>, change: impl FnOnce(&mut SourceOptions)) {
+    let mut changed = options.borrow().clone();
+    change(&mut changed);
+    options.send_replace(changed);
+}
+
+fn apply_message(message: &OscMessage, dsp_state: &Option<DspState>, options: &watch::Sender<SourceOptions>) -> Result<()> {
+    let path = match message.addr.strip_prefix("/audimon/") {
+        Some(path) => path.split('/').collect::<Vec<&str>>(),
+        None => bail!("Only /audimon/... addresses are understood"),
+    };
+    let number = || {
+        message
+            .args
+            .first()
+            .and_then(as_number)
+            .ok_or_else(|| anyhow::Error::msg("Expected a number"))
+    };
+    match path[..] {
+        ["volume"] => set_dsp_param(dsp_state, "volume", number()?),
+        ["layer", layer, control @ ("gain" | "mute")] => {
+            set_dsp_param(dsp_state, &format!("layers/{}/{}", layer, control), number()?)
+        }
+        ["param", ..] if path.len() > 1 => set_dsp_param(dsp_state, &path[1..].join("/"), number()?),
+        ["smoothing"] => {
+            let smoothing = number()?;
+            if smoothing.is_nan() || smoothing <= 0. || smoothing > 1. {
+                bail!("Smoothing must be more than 0 and at most 1");
+            }
+            change_options(options, |options| options.smoothing = smoothing);
+            Ok(())
+        }
+        ["per_core"] => {
+            let per_core = number()? != 0.;
+            change_options(options, |options| options.per_core = per_core);
+            Ok(())
+        }
+        ["drones_by"] => {
+            let drones_by = match message.args.first() {
+                Some(OscType::String(metric)) if metric == "cpu" => DroneMetric::Cpu,
+                Some(OscType::String(metric)) if metric == "memory" => DroneMetric::Memory,
+                _ => bail!("Expected \"cpu\" or \"memory\""),
+            };
+            change_options(options, |options| options.drones_by = drones_by);
+            Ok(())
+        }
+        ["poll_ms", source] if POLLED_SOURCES.contains(&source) => {
+            let ms = number()?;
+            if ms.is_nan() || ms < 1. {
+                bail!("Poll intervals must be at least 1ms");
+            }
+            let interval = Duration::from_millis(ms as u64);
+            change_options(options, |options| {
+                let intervals = &mut options.poll_intervals;
+                match source {
+                    "cpu" => intervals.cpu = interval,
+                    "memory" => intervals.memory = interval,
+                    "network" => intervals.network = interval,
+                    "disk" => intervals.disk = interval,
+                    _ => intervals.processes = interval,
+                }
+            });
+            Ok(())
+        }
+        _ => bail!("Nothing lives at this address"),
+    }
+}
+
+fn apply_packet(packet: OscPacket, dsp_state: &Option<DspState>, options: &watch::Sender<SourceOptions>) {
+    match packet {
+        OscPacket::Message(message) => {
+            if let Err(err) = apply_message(&message, dsp_state, options) {
+                println!("Ignoring OSC message to {}: {:#}", message.addr, err);
+            }
+        }
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                apply_packet(packet, dsp_state, options);
+            }
+        }
+    }
+}
+
+pub async fn serve(address: SocketAddr, dsp_state: Option<DspState>, options: Arc<watch::Sender<SourceOptions>>) -> Result<()> {
+    let socket = UdpSocket::bind(address).await?;
+    println!("Listening for OSC on {}", address);
+    tokio::spawn(async move {
+        let mut buffer = [0u8; rosc::decoder::MTU];
+        loop {
+            let size = match socket.recv_from(&mut buffer).await {
+                Ok((size, _)) => size,
+                Err(err) => {
+                    println!("Couldn't receive OSC: {}", err);
+                    continue;
+                }
+            };
+            match rosc::decoder::decode(&buffer[..size]) {
+                Ok(packet) => apply_packet(packet, &dsp_state, &options),
+                Err(err) => println!("Couldn't decode OSC packet: {:?}", err),
+            }
+        }
+    });
+    Ok(())
+}
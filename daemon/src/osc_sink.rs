@@ -0,0 +1,78 @@
+// Sends the metrics themselves as OSC over UDP, for doing the sound design in
+// SuperCollider, a DAW or anything else that speaks OSC. After every poll of a source:
+//   /audimon/metric/<source>/<channel> <level: float>
+//       for level channels like /audimon/metric/cpu/cpu_load, every poll
+//   /audimon/metric/<source>/<channel> <count: int>
+//       for counted channels like /audimon/metric/network/packets_in, when the count isn't 0
+//   /audimon/metric/<source>/<channel> <velocity: float> <value: float>...
+//       once per event, with the channel's values in order, like
+//       /audimon/metric/processes/process_exit <velocity> <pan> <size> <age> <work>
+// Levels are sent as measured: most are in [0, 1], drone pans in [-1, 1], and swap_in and
+// swap_out (pages a second) and disk_read_mb and disk_write_mb (MB a second) have no upper
+// limit. Event velocities and values are in [0, 1], except pans which are in [-1, 1].
+use anyhow::Result;
+use rosc::{OscMessage, OscPacket, OscType};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use crate::metrics::Reading;
+use crate::patchbay::{MetricTap, Snapshot};
+
+// Snapshots waiting to be sent before new ones get dropped
+const TAP_CAPACITY: usize = 256;
+
+fn messages(snapshot: Snapshot) -> Vec<OscMessage> {
+    let mut messages = Vec::new();
+    for (channel, reading) in snapshot.channels.iter().zip(snapshot.readings) {
+        let addr = format!("/audimon/metric/{}/{}", snapshot.source, channel.name);
+        match reading {
+            Reading::Level(level) => messages.push(OscMessage {
+                addr,
+                args: vec![OscType::Float(level)],
+            }),
+            Reading::Count(0) => {}
+            Reading::Count(num) => messages.push(OscMessage {
+                addr,
+                args: vec![OscType::Int(num.min(i32::MAX as usize) as i32)],
+            }),
+            Reading::Events(events) => messages.extend(events.into_iter().map(|event| OscMessage {
+                addr: addr.clone(),
+                args: std::iter::once(event.velocity)
+                    .chain(event.values)
+                    .map(OscType::Float)
+                    .collect(),
+            })),
+        }
+    }
+    messages
+}
+
+// Returns the tap to hand to the pollers
+pub async fn osc_sink(target: SocketAddr) -> Result<MetricTap> {
+    let local: SocketAddr = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse()?;
+    let socket = UdpSocket::bind(local).await?;
+    let (tap, mut snapshots) = mpsc::channel::<Snapshot>(TAP_CAPACITY);
+    println!("Sending metrics as OSC to {}", target);
+    tokio::spawn(async move {
+        while let Some(snapshot) = snapshots.recv().await {
+            for message in messages(snapshot) {
+                let packet = match rosc::encoder::encode(&OscPacket::Message(message)) {
+                    Ok(packet) => packet,
+                    Err(err) => {
+                        println!("Couldn't encode OSC message: {:?}", err);
+                        continue;
+                    }
+                };
+                match socket.send_to(&packet, target).await {
+                    // Nobody listening yet is fine, they'll hear the next one
+                    Err(err) if err.kind() != ErrorKind::ConnectionRefused => {
+                        println!("Couldn't send OSC to {}: {}", target, err)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    });
+    Ok(tap)
+}
@@ -0,0 +1,426 @@
+// Wires the channels of every metric source into the DSP's inputs by name, polls each
+// source on its own thread and renders their readings into audio frames.
+use rand::Rng;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use crate::audio::AudioConfig;
+use crate::metrics::{Channel, ChannelKind, Event, MetricSource, Reading, SourceOptions};
+
+fn ramp_buffer(buffer: &mut [f32], from: f32, to: f32) {
+    let frame_size = buffer.len();
+    for (i, sample) in buffer.iter_mut().enumerate() {
+        let ratio = i as f32 / frame_size as f32;
+        *sample = from * (1. - ratio) + to * ratio
+    }
+}
+
+// Sorted, at most one event every other sample
+fn event_positions(num: usize, frame_size: usize) -> Vec<usize> {
+    let mut rng = rand::thread_rng();
+    let mut positions = (0..num)
+        .map(|_| {
+            // at max, i want the event buffer to be alternating 1s and 0s
+            let position = rng.gen::<usize>() % (frame_size / 2).max(1);
+            position * 2
+        })
+        .collect::<Vec<usize>>();
+    positions.sort_unstable();
+    positions
+}
+
+fn mount_positive_samples_in_buffer(buffer: &mut [f32], positions: &[usize], events: &[Event]) {
+    buffer.fill(0.);
+    for (i, position) in positions.iter().enumerate() {
+        buffer[*position] += events.get(i).map(|event| event.velocity).unwrap_or(1.);
+    }
+}
+
+// Each event's value is held from its impulse until the next one
+// 1   0   0   0   1
+// 0.4 0.4 0.4 0.4 -.9
+fn mount_held_values_in_buffer(
+    buffer: &mut [f32],
+    positions: &[usize],
+    events: &[Event],
+    value: usize,
+    prev_value: &mut f32,
+) {
+    let mut start = 0;
+    for (position, event) in positions.iter().zip(events.iter()) {
+        for sample in buffer[start..*position].iter_mut() {
+            *sample = *prev_value;
+        }
+        *prev_value = event.values.get(value).copied().unwrap_or(0.);
+        start = *position;
+    }
+    for sample in buffer[start..].iter_mut() {
+        *sample = *prev_value;
+    }
+}
+
+// How many of the remaining things to render this frame so they're spread evenly over
+// the frames left until the next poll
+fn share_for_frame(remaining: usize, frames_left: usize) -> usize {
+    remaining.div_ceil(frames_left)
+}
+
+struct PatchedChannel {
+    channel: Channel,
+    // The DSP inputs this channel feeds: the level or the impulses, then each held value
+    inputs: Vec<Option<usize>>,
+    // Frames until the next poll is due, for interpolating levels and spreading events
+    frames_left: usize,
+    prev_level: f32,
+    target_level: f32,
+    pending_events: VecDeque<Event>,
+    pending_count: usize,
+    prev_values: Vec<f32>,
+}
+
+impl PatchedChannel {
+    fn new(channel: Channel, input_names: &[String]) -> PatchedChannel {
+        let find_input = |name: &str| input_names.iter().position(|input_name| input_name == name);
+        let mut inputs = vec![find_input(&channel.name)];
+        let mut num_values = 0;
+        if let ChannelKind::Events { values } = channel.kind {
+            num_values = values.len();
+            inputs.extend(values.iter().map(|value| find_input(&format!("{}_{}", channel.name, value))));
+        }
+        PatchedChannel {
+            channel,
+            inputs,
+            frames_left: 1,
+            prev_level: 0.,
+            target_level: 0.,
+            pending_events: VecDeque::new(),
+            pending_count: 0,
+            prev_values: vec![0.; num_values],
+        }
+    }
+
+    // frames_per_poll is how many frames until the source's next reading
+    fn receive(&mut self, reading: Reading, frames_per_poll: usize) {
+        match reading {
+            Reading::Level(level) => self.target_level = level,
+            Reading::Count(num) => self.pending_count += num,
+            Reading::Events(events) => self.pending_events.extend(events),
+        }
+        self.frames_left = frames_per_poll;
+    }
+
+    fn render(&mut self, frames: &mut [Vec<f32>]) {
+        match self.channel.kind {
+            ChannelKind::Level { .. } => {
+                // Ramp linearly so we arrive at the latest reading just as the next one is due
+                let level = self.prev_level + (self.target_level - self.prev_level) / self.frames_left as f32;
+                if let Some(input) = self.inputs[0] {
+                    ramp_buffer(&mut frames[input], self.prev_level, level);
+                }
+                self.prev_level = level;
+            }
+            ChannelKind::Events { .. } => {
+                let num = share_for_frame(self.pending_count, self.frames_left);
+                self.pending_count -= num;
+                let num_events = share_for_frame(self.pending_events.len(), self.frames_left);
+                let events = self.pending_events.drain(..num_events).collect::<Vec<Event>>();
+
+                let frame_size = frames.first().map(Vec::len).unwrap_or(0);
+                let positions = event_positions(events.len(), frame_size);
+                if let Some(input) = self.inputs[0] {
+                    mount_positive_samples_in_buffer(&mut frames[input], &positions, &events);
+                    for position in event_positions(num, frame_size) {
+                        frames[input][position] += 1.;
+                    }
+                }
+                for (value, prev_value) in self.prev_values.iter_mut().enumerate() {
+                    if let Some(input) = self.inputs[value + 1] {
+                        mount_held_values_in_buffer(&mut frames[input], &positions, &events, value, prev_value);
+                    } else {
+                        *prev_value = events.last().and_then(|event| event.values.get(value).copied()).unwrap_or(*prev_value);
+                    }
+                }
+            }
+        }
+        self.frames_left = (self.frames_left - 1).max(1);
+    }
+}
+
+// Readings a source has published that the renderer hasn't picked up yet
+struct Posted {
+    // One per channel
+    readings: Vec<Option<Reading>>,
+    // Until the next poll, which can change when the source is reconfigured
+    poll_interval: Duration,
+}
+
+type Mailbox = Arc<Mutex<Posted>>;
+
+fn post(pending: &mut Option<Reading>, reading: Reading) {
+    *pending = Some(match (pending.take(), reading) {
+        (Some(Reading::Events(mut events)), Reading::Events(more)) => {
+            events.extend(more);
+            Reading::Events(events)
+        }
+        (Some(Reading::Count(num)), Reading::Count(more)) => Reading::Count(num + more),
+        // Only the latest level matters
+        (_, reading) => reading,
+    });
+}
+
+// One poll of a source, for sinks that want the metrics themselves rather than audio
+#[derive(Clone)]
+pub struct Snapshot {
+    pub source: &'static str,
+    pub channels: Arc<Vec<Channel>>,
+    // One per channel
+    pub readings: Vec<Reading>,
+    pub taken: Instant,
+    // How long the events and counts were collected over, up to `taken`
+    pub poll_interval: Duration,
+}
+
+pub type MetricTap = mpsc::Sender<Snapshot>;
+
+// The mailbox is weak so nothing piles up in it once there's no patchbay to empty it
+fn spawn_poller(
+    mut source: Box<dyn MetricSource>,
+    mailbox: Weak<Mutex<Posted>>,
+    mut options: watch::Receiver<SourceOptions>,
+    taps: Vec<MetricTap>,
+) {
+    let channels = Arc::new(source.channels());
+    // For waiting on `options` from a thread of our own
+    let runtime = tokio::runtime::Handle::current();
+    thread::Builder::new()
+        .name(format!("poll {}", source.name()))
+        .spawn(move || loop {
+            if options.has_changed().unwrap_or(false) {
+                source.reconfigure(&options.borrow_and_update());
+            }
+            let started = Instant::now();
+            let readings = source.poll();
+            assert_eq!(readings.len(), channels.len(), "{} must give one reading per channel", source.name());
+            if !taps.is_empty() {
+                let snapshot = Snapshot {
+                    source: source.name(),
+                    channels: Arc::clone(&channels),
+                    readings: readings.clone(),
+                    taken: Instant::now(),
+                    poll_interval: source.poll_interval(),
+                };
+                // A tap that can't keep up misses snapshots rather than holding up polling
+                for tap in taps.iter() {
+                    let _ = tap.try_send(snapshot.clone());
+                }
+            }
+            if let Some(mailbox) = mailbox.upgrade() {
+                let mut posted = mailbox.lock().unwrap();
+                for (pending, reading) in posted.readings.iter_mut().zip(readings) {
+                    post(pending, reading);
+                }
+                posted.poll_interval = source.poll_interval();
+            }
+            // Options that change meanwhile, like a shorter poll interval, apply right away
+            // rather than after the old interval is up
+            while let Some(remaining) = source.poll_interval().checked_sub(started.elapsed()) {
+                match runtime.block_on(async { tokio::time::timeout(remaining, options.changed()).await }) {
+                    Ok(Ok(())) => source.reconfigure(&options.borrow_and_update()),
+                    // Nothing can change them any more
+                    Ok(Err(_)) => {
+                        thread::sleep(remaining);
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            }
+        })
+        .expect("Failed to spawn metric polling thread");
+}
+
+// A source being polled on its own thread
+pub struct PolledSource {
+    name: &'static str,
+    channels: Vec<Channel>,
+    mailbox: Mailbox,
+}
+
+// Starts polling every source on its own thread. Sources pick up new options whenever
+// `options` changes, and every poll is also sent to each of `taps`.
+pub fn start_polling(
+    sources: Vec<Box<dyn MetricSource>>,
+    options: watch::Receiver<SourceOptions>,
+    taps: Vec<MetricTap>,
+) -> Vec<PolledSource> {
+    sources
+        .into_iter()
+        .map(|source| {
+            let channels = source.channels();
+            let mailbox = Arc::new(Mutex::new(Posted {
+                readings: (0..channels.len()).map(|_| None).collect(),
+                poll_interval: source.poll_interval(),
+            }));
+            let name = source.name();
+            spawn_poller(source, Arc::downgrade(&mailbox), options.clone(), taps.clone());
+            PolledSource { name, channels, mailbox }
+        })
+        .collect()
+}
+
+struct PatchedSource {
+    mailbox: Mailbox,
+    channels: Vec<PatchedChannel>,
+}
+
+pub struct Patchbay {
+    sources: Vec<PatchedSource>,
+    frame_duration: Duration,
+    // One per DSP input; inputs nothing is patched into stay silent
+    frames: Vec<Vec<f32>>,
+}
+
+impl Patchbay {
+    pub fn new(sources: Vec<PolledSource>, input_names: &[String], config: AudioConfig) -> Patchbay {
+        let sources = sources
+            .into_iter()
+            .map(|source| {
+                let channels = source
+                    .channels
+                    .into_iter()
+                    .map(|channel| PatchedChannel::new(channel, input_names))
+                    .collect::<Vec<PatchedChannel>>();
+                for channel in channels.iter() {
+                    if channel.inputs.iter().all(Option::is_none) {
+                        println!("{} channel {} isn't used by the DSP", source.name, channel.channel.name);
+                    }
+                }
+                PatchedSource { mailbox: source.mailbox, channels }
+            })
+            .collect::<Vec<PatchedSource>>();
+
+        for (input, input_name) in input_names.iter().enumerate() {
+            let patched = sources
+                .iter()
+                .flat_map(|source| source.channels.iter())
+                .any(|channel| channel.inputs.contains(&Some(input)));
+            if !patched {
+                println!("DSP input {} has no metric source, leaving it silent", input_name);
+            }
+        }
+
+        Patchbay {
+            sources,
+            frame_duration: config.frame_duration,
+            frames: vec![vec![0f32; config.frame_size()]; input_names.len()],
+        }
+    }
+
+    // Picks up whatever the sources have published since the last frame and renders the
+    // next frame of every input. Never waits for a source to be polled.
+    pub fn update(&mut self) {
+        for patched_source in self.sources.iter_mut() {
+            let (readings, poll_interval) = {
+                let mut posted = patched_source.mailbox.lock().unwrap();
+                let readings = posted.readings.iter_mut().map(Option::take).collect::<Vec<Option<Reading>>>();
+                (readings, posted.poll_interval)
+            };
+            let frames_per_poll = (poll_interval.as_secs_f32() / self.frame_duration.as_secs_f32()).round().max(1.) as usize;
+            for (channel, reading) in patched_source.channels.iter_mut().zip(readings) {
+                if let Some(reading) = reading {
+                    channel.receive(reading, frames_per_poll);
+                }
+                channel.render(&mut self.frames);
+            }
+        }
+    }
+
+    pub fn inputs(&self) -> Vec<&[f32]> {
+        self.frames.iter().map(|frame| &frame[..]).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smallvec::smallvec;
+
+    fn event(velocity: f32, value: f32) -> Event {
+        Event { velocity, values: smallvec![value] }
+    }
+
+    fn frames(num_inputs: usize, frame_size: usize) -> Vec<Vec<f32>> {
+        vec![vec![0.; frame_size]; num_inputs]
+    }
+
+    #[test]
+    fn shares_spread_whats_left_evenly() {
+        assert_eq!(share_for_frame(10, 5), 2);
+        // Rounds up, so nothing is left over by the last frame
+        assert_eq!(share_for_frame(3, 2), 2);
+        assert_eq!(share_for_frame(1, 5), 1);
+        assert_eq!(share_for_frame(7, 1), 7);
+        assert_eq!(share_for_frame(0, 3), 0);
+    }
+
+    #[test]
+    fn holds_each_value_until_the_next_event() {
+        let mut buffer = vec![9.; 8];
+        let mut prev_value = 0.5;
+        mount_held_values_in_buffer(&mut buffer, &[2, 6], &[event(1., 0.1), event(1., -0.9)], 0, &mut prev_value);
+        assert_eq!(buffer, vec![0.5, 0.5, 0.1, 0.1, 0.1, 0.1, -0.9, -0.9]);
+        assert_eq!(prev_value, -0.9);
+
+        // Without events the last value carries on
+        mount_held_values_in_buffer(&mut buffer, &[], &[], 0, &mut prev_value);
+        assert_eq!(buffer, vec![-0.9; 8]);
+    }
+
+    #[test]
+    fn levels_ramp_to_the_reading_by_the_next_poll() {
+        let mut channel = PatchedChannel::new(Channel::level("cpu_load"), &["cpu_load".to_owned()]);
+        let mut frames = frames(1, 4);
+        channel.receive(Reading::Level(1.), 4);
+        channel.render(&mut frames);
+        assert_eq!(frames[0], vec![0., 0.0625, 0.125, 0.1875]);
+        for _ in 0..3 {
+            channel.render(&mut frames);
+        }
+        assert_eq!(channel.prev_level, 1.);
+        // And stays there until there's another reading
+        channel.render(&mut frames);
+        assert_eq!(frames[0], vec![1.; 4]);
+    }
+
+    #[test]
+    fn events_are_spread_over_the_frames_until_the_next_poll() {
+        let inputs = ["exit".to_owned(), "exit_pan".to_owned()];
+        let mut channel = PatchedChannel::new(Channel::events("exit", &["pan"]), &inputs);
+        let mut frames = frames(2, 16);
+        channel.receive(Reading::Events((0..4).map(|i| event(0.5, i as f32)).collect()), 2);
+        channel.receive(Reading::Count(3), 2);
+
+        let mut impulses = 0.;
+        for _ in 0..2 {
+            channel.render(&mut frames);
+            impulses += frames[0].iter().sum::<f32>();
+        }
+        // Four events at 0.5 and three counted ones at 1
+        assert_eq!(impulses, 5.);
+        assert!(channel.pending_events.is_empty());
+        assert_eq!(channel.pending_count, 0);
+        // The last event's value is held to the end of the frame
+        assert_eq!(*frames[1].last().unwrap(), 3.);
+    }
+
+    #[test]
+    fn values_without_an_input_are_still_kept() {
+        let mut channel = PatchedChannel::new(Channel::events("exit", &["pan"]), &["exit".to_owned()]);
+        let mut frames = frames(1, 16);
+        channel.receive(Reading::Events(vec![event(1., 0.3)]), 1);
+        channel.render(&mut frames);
+        assert_eq!(channel.prev_values, vec![0.3]);
+        assert_eq!(frames[0].iter().sum::<f32>(), 1.);
+    }
+}
@@ -0,0 +1,386 @@
+// An audio flight recorder: writes the rendered audio as Ogg/Opus files of a fixed length,
+// named by when they started, like audimon-20220314T090000Z.opus, and deletes the ones
+// past the retention limit. index.json in the same directory lists what's there:
+//   {"segments": [{"file": "...", "started": "<RFC 3339>", "ended": "<RFC 3339>"}, ...]}
+// The segment being written has no end yet, and neither does one cut short by a crash.
+// An index that can't be read is moved aside and rebuilt from the file names.
+// Segments are cut by the clock on the wall, and audio the fan-out dropped because we fell
+// behind is made up with silence, so a file's length matches the time it covers.
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use crate::audio::AudioConfig;
+use crate::config::SegmentsSection;
+use crate::fanout;
+use crate::opus::OpusEncoder;
+
+const INDEX_FILE: &str = "index.json";
+const STAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+// Ogg Opus positions always count 48kHz samples, whatever the real rate
+const GRANULE_RATE: u128 = 48000;
+// How much of the start the player should skip, which is the encoder's lookahead
+const PRE_SKIP: u16 = 312;
+// Falling further behind the clock than this means frames were dropped. Less would fill
+// in silence for a sound card's clock that just runs a little slow.
+const GAP_TOLERANCE: Duration = Duration::from_millis(250);
+
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    file: String,
+    started: DateTime<Utc>,
+    ended: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Index {
+    segments: Vec<IndexEntry>,
+}
+
+impl Index {
+    fn load(dir: &Path) -> Result<Index> {
+        let path = dir.join(INDEX_FILE);
+        let json = match fs::read(&path) {
+            Ok(json) => json,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Index::default()),
+            Err(err) => return Err(err).with_context(|| format!("Couldn't read {}", path.display())),
+        };
+        match serde_json::from_slice(&json) {
+            Ok(index) => Ok(index),
+            Err(err) => {
+                // The recordings are all still there, so a bad index shouldn't stop us
+                let aside = path.with_extension("json.corrupt");
+                println!(
+                    "Couldn't parse {}, moving it to {} and rebuilding it from the file names: {}",
+                    path.display(),
+                    aside.display(),
+                    err
+                );
+                fs::rename(&path, &aside).with_context(|| format!("Couldn't move {} aside", path.display()))?;
+                Index::rebuild(dir)
+            }
+        }
+    }
+
+    // Every segment in the directory, from when its name says it started. When they ended
+    // is lost, so retention goes by when they started.
+    fn rebuild(dir: &Path) -> Result<Index> {
+        let mut segments = fs::read_dir(dir)
+            .with_context(|| format!("Couldn't list {}", dir.display()))?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter_map(|file| Some(IndexEntry { started: segment_started(&file)?, file, ended: None }))
+            .collect::<Vec<IndexEntry>>();
+        // Shorter first puts audimon-<stamp>.opus before audimon-<stamp>-2.opus
+        segments.sort_by(|a, b| (a.started, a.file.len(), &a.file).cmp(&(b.started, b.file.len(), &b.file)));
+        Ok(Index { segments })
+    }
+
+    // Deletes every segment but the current one, which is the last, that ended before the
+    // retention limit
+    fn expire(&mut self, dir: &Path, retention: chrono::Duration) {
+        let cutoff = Utc::now() - retention;
+        let current = self.segments.pop().expect("The current segment is always in the index");
+        self.segments.retain(|entry| {
+            if entry.ended.unwrap_or(entry.started) >= cutoff {
+                return true;
+            }
+            match fs::remove_file(dir.join(&entry.file)) {
+                Ok(()) => println!("Deleted {}, it's past the retention limit", entry.file),
+                // Already gone is just as good
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => {
+                    println!("Couldn't delete {}, will try again next segment: {}", entry.file, err);
+                    return true;
+                }
+            }
+            false
+        });
+        self.segments.push(current);
+    }
+
+    fn save(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(INDEX_FILE);
+        // Written next to it and moved over, so there's always a whole index to read
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, serde_json::to_vec_pretty(self)?).with_context(|| format!("Couldn't write {}", partial.display()))?;
+        fs::rename(&partial, &path).with_context(|| format!("Couldn't replace {}", path.display()))?;
+        Ok(())
+    }
+}
+
+// Like audimon-20220314T090000Z.opus, or audimon-20220314T090000Z-2.opus
+fn segment_started(file: &str) -> Option<DateTime<Utc>> {
+    let stamp = file.strip_prefix("audimon-")?.strip_suffix(".opus")?;
+    let stamp = stamp.split_once('-').map(|(stamp, _)| stamp).unwrap_or(stamp);
+    let started = NaiveDateTime::parse_from_str(stamp, STAMP_FORMAT).ok()?;
+    Some(Utc.from_utc_datetime(&started))
+}
+
+fn opus_head(audio_config: AudioConfig) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    // Version, then channels
+    head.extend([1, 2]);
+    head.extend(PRE_SKIP.to_le_bytes());
+    head.extend(audio_config.sample_rate.to_le_bytes());
+    // Output gain, then channel mapping family 0 for plain stereo
+    head.extend(0i16.to_le_bytes());
+    head.push(0);
+    head
+}
+
+fn opus_tags(started: DateTime<Utc>) -> Vec<u8> {
+    let vendor = b"audimon";
+    let comment = format!("DATE={}", started.to_rfc3339());
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend((vendor.len() as u32).to_le_bytes());
+    tags.extend(vendor);
+    // How many comments, then each one
+    tags.extend(1u32.to_le_bytes());
+    tags.extend((comment.len() as u32).to_le_bytes());
+    tags.extend(comment.as_bytes());
+    tags
+}
+
+struct Segment {
+    writer: PacketWriter<'static, File>,
+    serial: u32,
+    started: Instant,
+    frames: u64,
+    granule: u64,
+    // The latest packet and its position, held back so the last one can end the stream
+    held: Option<(Vec<u8>, u64)>,
+}
+
+impl Segment {
+    fn create(path: &Path, audio_config: AudioConfig, started: DateTime<Utc>) -> Result<Segment> {
+        let file = File::create(path).with_context(|| format!("Couldn't create {}", path.display()))?;
+        let mut segment = Segment {
+            writer: PacketWriter::new(file),
+            serial: rand::random(),
+            started: Instant::now(),
+            frames: 0,
+            granule: 0,
+            held: None,
+        };
+        // Each header gets a page of its own
+        for header in [opus_head(audio_config), opus_tags(started)] {
+            segment.writer.write_packet(header, segment.serial, PacketWriteEndInfo::EndPage, 0)?;
+        }
+        Ok(segment)
+    }
+
+    fn write(&mut self, packet: Vec<u8>, granules: u64) -> Result<()> {
+        self.frames += 1;
+        self.granule += granules;
+        if let Some((packet, granule)) = self.held.replace((packet, self.granule)) {
+            self.writer.write_packet(packet, self.serial, PacketWriteEndInfo::NormalPacket, granule)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if let Some((packet, granule)) = self.held.take() {
+            self.writer.write_packet(packet, self.serial, PacketWriteEndInfo::EndStream, granule)?;
+        }
+        Ok(())
+    }
+}
+
+struct Recorder {
+    dir: PathBuf,
+    audio_config: AudioConfig,
+    encoder: OpusEncoder,
+    segment_length: Duration,
+    granules_per_frame: u64,
+    retention: chrono::Duration,
+    index: Index,
+    segment: Segment,
+}
+
+impl Recorder {
+    fn open(dir: &Path, audio_config: AudioConfig, settings: &SegmentsSection) -> Result<Recorder> {
+        fs::create_dir_all(dir).with_context(|| format!("Couldn't create {}", dir.display()))?;
+        let encoder = OpusEncoder::new(audio_config)?;
+        let mut index = Index::load(dir)?;
+        let segment = Recorder::start_segment(dir, audio_config, &mut index)?;
+        let frame_micros = audio_config.frame_duration.as_micros();
+        let mut recorder = Recorder {
+            dir: dir.to_owned(),
+            audio_config,
+            encoder,
+            segment_length: Duration::from_secs(settings.segment_minutes * 60),
+            granules_per_frame: (frame_micros * GRANULE_RATE / 1_000_000) as u64,
+            retention: chrono::Duration::hours(settings.retention_hours as i64),
+            index,
+            segment,
+        };
+        recorder.index.expire(&recorder.dir, recorder.retention);
+        recorder.index.save(dir)?;
+        Ok(recorder)
+    }
+
+    // Starts a new file and adds it to the index, without saving the index
+    fn start_segment(dir: &Path, audio_config: AudioConfig, index: &mut Index) -> Result<Segment> {
+        let started = Utc::now();
+        let stamp = started.format(STAMP_FORMAT);
+        // Never overwrite a recording, like after restarting within a second
+        let file = (1..)
+            .map(|num| match num {
+                1 => format!("audimon-{}.opus", stamp),
+                _ => format!("audimon-{}-{}.opus", stamp, num),
+            })
+            .find(|file| !dir.join(file).exists())
+            .unwrap();
+        let segment = Segment::create(&dir.join(&file), audio_config, started)?;
+        println!("Recording audio to {}", dir.join(&file).display());
+        index.segments.push(IndexEntry { file, started, ended: None });
+        Ok(segment)
+    }
+
+    fn write(&mut self, frame: &[(i16, i16)]) -> Result<()> {
+        if self.segment.started.elapsed() >= self.segment_length {
+            let next = Recorder::start_segment(&self.dir, self.audio_config, &mut self.index)?;
+            std::mem::replace(&mut self.segment, next).finish()?;
+            let ended = self.index.segments.len() - 2;
+            self.index.segments[ended].ended = Some(Utc::now());
+            self.index.expire(&self.dir, self.retention);
+            self.index.save(&self.dir)?;
+        }
+        let frame_duration = self.audio_config.frame_duration;
+        let behind = self.segment.started.elapsed().saturating_sub(frame_duration * self.segment.frames as u32);
+        if behind > GAP_TOLERANCE {
+            // All but the slot this frame goes in
+            let missing = (behind.as_micros() / frame_duration.as_micros()).saturating_sub(1) as u64;
+            let silence = vec![(0, 0); frame.len()];
+            for _ in 0..missing {
+                let packet = self.encoder.encode(&silence)?.to_vec();
+                self.segment.write(packet, self.granules_per_frame)?;
+            }
+        }
+        let packet = self.encoder.encode(frame)?.to_vec();
+        self.segment.write(packet, self.granules_per_frame)
+    }
+
+    // Writes frames until there are no more or writing fails, then finishes up
+    fn run(mut self, mut frames: mpsc::Receiver<Vec<(i16, i16)>>) -> Result<()> {
+        while let Some(frame) = frames.blocking_recv() {
+            if let Err(err) = self.write(&frame) {
+                // Like a full disk. The other sinks carry on without us.
+                println!("Couldn't record to {}, stopped recording: {:#}", self.dir.display(), err);
+                break;
+            }
+        }
+        drop(frames);
+        self.close()
+    }
+
+    fn close(self) -> Result<()> {
+        let Recorder { dir, mut index, segment, .. } = self;
+        segment.finish()?;
+        if let Some(entry) = index.segments.last_mut() {
+            entry.ended = Some(Utc::now());
+        }
+        index.save(&dir)?;
+        println!("Finished recording to {}", dir.display());
+        Ok(())
+    }
+}
+
+// Returns the recording task, which finishes the current segment once `shutdown` goes
+// true, or as soon as writing fails
+pub fn segment_sink(
+    audio_buf_rx: mpsc::Receiver<Vec<(i16, i16)>>,
+    dir: &Path,
+    audio_config: AudioConfig,
+    settings: &SegmentsSection,
+    shutdown: watch::Receiver<bool>,
+) -> Result<JoinHandle<Result<()>>> {
+    let recorder = Recorder::open(dir, audio_config, settings)?;
+    // Encoding, writing, rotating and deleting all block, so they get a thread of their own
+    Ok(fanout::spawn_blocking_writer(audio_buf_rx, shutdown, move |frames| recorder.run(frames)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(file: &str, started: DateTime<Utc>, ended: Option<DateTime<Utc>>) -> IndexEntry {
+        IndexEntry { file: file.to_owned(), started, ended }
+    }
+
+    fn files(index: &Index) -> Vec<&str> {
+        index.segments.iter().map(|entry| &entry.file[..]).collect()
+    }
+
+    #[test]
+    fn expires_segments_past_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let hours_ago = |hours| Utc::now() - chrono::Duration::hours(hours);
+        for file in ["old.opus", "crashed.opus", "recent.opus", "current.opus"] {
+            fs::write(dir.path().join(file), b"").unwrap();
+        }
+        let mut index = Index {
+            segments: vec![
+                entry("old.opus", hours_ago(12), Some(hours_ago(11))),
+                // Never ended, so it goes by when it started
+                entry("crashed.opus", hours_ago(10), None),
+                // Already deleted by hand
+                entry("gone.opus", hours_ago(9), Some(hours_ago(8))),
+                // Started before the limit, but ended after it
+                entry("recent.opus", hours_ago(7), Some(hours_ago(4))),
+                // However old, the one being written stays
+                entry("current.opus", hours_ago(20), None),
+            ],
+        };
+        index.expire(dir.path(), chrono::Duration::hours(5));
+        assert_eq!(files(&index), vec!["recent.opus", "current.opus"]);
+        for (file, kept) in [("old.opus", false), ("crashed.opus", false), ("recent.opus", true), ("current.opus", true)] {
+            assert_eq!(dir.path().join(file).exists(), kept, "{}", file);
+        }
+    }
+
+    #[test]
+    fn reads_when_segments_started_from_their_names() {
+        let started = "2022-03-14T09:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(segment_started("audimon-20220314T090000Z.opus"), Some(started));
+        assert_eq!(segment_started("audimon-20220314T090000Z-2.opus"), Some(started));
+        for file in ["index.json", "audimon-20220314T090000Z.ogg", "other-20220314T090000Z.opus", "audimon-now.opus"] {
+            assert_eq!(segment_started(file), None, "{}", file);
+        }
+    }
+
+    #[test]
+    fn rebuilds_a_corrupt_index() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["audimon-20220314T100000Z.opus", "audimon-20220314T090000Z-2.opus", "audimon-20220314T090000Z.opus", "notes.txt"] {
+            fs::write(dir.path().join(file), b"").unwrap();
+        }
+        fs::write(dir.path().join(INDEX_FILE), b"{\"segments\": [").unwrap();
+        let index = Index::load(dir.path()).unwrap();
+        assert_eq!(
+            files(&index),
+            vec!["audimon-20220314T090000Z.opus", "audimon-20220314T090000Z-2.opus", "audimon-20220314T100000Z.opus"]
+        );
+        assert!(index.segments.iter().all(|entry| entry.ended.is_none()));
+        assert!(!dir.path().join(INDEX_FILE).exists());
+        assert_eq!(fs::read(dir.path().join("index.json.corrupt")).unwrap(), b"{\"segments\": [");
+    }
+
+    #[test]
+    fn saved_indexes_load() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Index::load(dir.path()).unwrap().segments.is_empty());
+        let started = "2022-03-14T09:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let index = Index { segments: vec![entry("audimon-20220314T090000Z.opus", started, None)] };
+        index.save(dir.path()).unwrap();
+        let loaded = Index::load(dir.path()).unwrap();
+        assert_eq!(files(&loaded), files(&index));
+        assert_eq!(loaded.segments[0].started, started);
+    }
+}
@@ -0,0 +1,79 @@
+// Records the rendered audio to a 16 bit stereo WAV file, to keep what an incident sounded
+// like. The header is brought up to date every few seconds, so the file plays even if the
+// daemon is killed, and properly finished when it shuts down. The sizes in a WAV header
+// are 32 bits, so recording stops once the file holds 4GB, a bit over 6 hours at 48kHz.
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use crate::audio::AudioConfig;
+use crate::fanout;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+// hound doesn't notice the data size wrapping, so stop with room left for the headers
+const MAX_DATA_BYTES: u64 = u32::MAX as u64 - 1024;
+const BYTES_PER_FRAME: u64 = 4;
+
+// Writes frames until there are no more, the file is full or writing fails, then
+// finishes the file
+fn record(
+    mut writer: hound::WavWriter<BufWriter<File>>,
+    path: PathBuf,
+    mut frames: mpsc::Receiver<Vec<(i16, i16)>>,
+) -> Result<()> {
+    let mut flushed = Instant::now();
+    let mut data_bytes = 0;
+    // True once the file is full
+    let written = (|| -> hound::Result<bool> {
+        while let Some(frame) = frames.blocking_recv() {
+            data_bytes += frame.len() as u64 * BYTES_PER_FRAME;
+            if data_bytes > MAX_DATA_BYTES {
+                return Ok(true);
+            }
+            for (left, right) in frame {
+                writer.write_sample(left)?;
+                writer.write_sample(right)?;
+            }
+            if flushed.elapsed() >= FLUSH_INTERVAL {
+                writer.flush()?;
+                flushed = Instant::now();
+            }
+        }
+        Ok(false)
+    })();
+    // The other sinks carry on without us
+    match written {
+        Ok(true) => println!("{} is as big as a WAV file gets, stopped recording", path.display()),
+        Ok(false) => {}
+        // Like a full disk
+        Err(err) => println!("Couldn't write to {}, stopped recording: {}", path.display(), err),
+    }
+    drop(frames);
+    writer.finalize().with_context(|| format!("Couldn't finish {}", path.display()))?;
+    println!("Saved audio recording to {}", path.display());
+    Ok(())
+}
+
+// Returns the recording task, which finishes the file once `shutdown` goes true, or as
+// soon as writing fails
+pub fn wav_sink(
+    audio_buf_rx: mpsc::Receiver<Vec<(i16, i16)>>,
+    path: &Path,
+    audio_config: AudioConfig,
+    shutdown: watch::Receiver<bool>,
+) -> Result<JoinHandle<Result<()>>> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: audio_config.sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let writer = hound::WavWriter::create(path, spec).with_context(|| format!("Couldn't create {}", path.display()))?;
+    println!("Recording audio to {}", path.display());
+    // Writing and flushing block, so they get a thread of their own
+    let path = path.to_owned();
+    Ok(fanout::spawn_blocking_writer(audio_buf_rx, shutdown, move |frames| record(writer, path, frames)))
+}
@@ -1,193 +1,403 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bytes::Bytes;
-use std::sync::Arc;
-use tokio::time::Duration;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::sync::{Arc, Mutex};
+use signalz::{Refusal, Signal};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use webrtc::media::Sample;
 
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_OPUS};
-use webrtc::api::APIBuilder;
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::api::{APIBuilder, API};
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
 use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::rtp_transceiver::rtp_codec::{
     RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
 };
 use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
 use webrtc::track::track_local::{TrackLocal};
+use crate::audio::AudioConfig;
+use crate::config::WebrtcSection;
+use crate::opus::OpusEncoder;
 
-pub async fn webrtc_sink(
-    mut audio_buf_rx: tokio::sync::mpsc::Receiver<Vec<(i16, i16)>>,
-    done_tx: tokio::sync::mpsc::Sender<()>,
-) -> Result<(), anyhow::Error> {
-    // Create a MediaEngine object to configure the supported codec
-    let mut m = MediaEngine::default();
+struct Peer {
+    connection: Arc<RTCPeerConnection>,
+    track: Arc<TrackLocalStaticSample>,
+    // Given back when they leave
+    _slot: OwnedSemaphorePermit,
+}
 
-    // Setup the codecs you want to use.
-    m.register_codec(
-        RTCRtpCodecParameters {
-            capability: RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_OPUS.to_owned(),
+// Every slot is taken, so a new listener has to wait for someone to leave
+#[derive(Debug)]
+struct Full(usize);
+
+impl std::fmt::Display for Full {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "There are already {} listeners, try again once someone leaves", self.0)
+    }
+}
+
+impl std::error::Error for Full {}
+
+// Everyone listening. Each peer gets its own track, and every track gets the same packets.
+#[derive(Default)]
+struct Sessions {
+    // Keyed by a random id, which is also what hangs up over HTTP, so it mustn't be
+    // guessable by other listeners
+    peers: Mutex<HashMap<String, Peer>>,
+}
+
+impl Sessions {
+    fn tracks(&self) -> Vec<(String, Arc<TrackLocalStaticSample>)> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, peer)| (id.clone(), Arc::clone(&peer.track)))
+            .collect()
+    }
+
+    // False if they'd already gone
+    async fn remove(&self, id: &str) -> bool {
+        let peer = self.peers.lock().unwrap().remove(id);
+        match peer {
+            Some(peer) => {
+                if let Err(err) = peer.connection.close().await {
+                    println!("Listener {} didn't close cleanly: {}", id, err);
+                }
+                println!("Listener {} left, {} still listening", id, self.peers.lock().unwrap().len());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct Listeners {
+    api: API,
+    ice_servers: Vec<RTCIceServer>,
+    sessions: Arc<Sessions>,
+    // One for each listener, connected or still connecting, up to max_listeners. Each holds
+    // on to UDP sockets and a peer connection.
+    slots: Arc<Semaphore>,
+    max_listeners: usize,
+}
+
+impl Listeners {
+    fn new(settings: &WebrtcSection) -> Result<Listeners> {
+        // Create a MediaEngine object to configure the supported codec
+        let mut m = MediaEngine::default();
+
+        // Setup the codecs you want to use.
+        m.register_codec(
+            RTCRtpCodecParameters {
+                capability: RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_OPUS.to_owned(),
+                    ..Default::default()
+                },
+                payload_type: 120,
                 ..Default::default()
             },
-            payload_type: 120,
-            ..Default::default()
-        },
-        RTPCodecType::Audio,
-    )?;
-
-    // Create a InterceptorRegistry. This is the user configurable RTP/RTCP Pipeline.
-    // This provides NACKs, RTCP Reports and other features. If you use `webrtc.NewPeerConnection`
-    // this is enabled by default. If you are manually managing You MUST create a InterceptorRegistry
-    // for each PeerConnection.
-    let mut registry = Registry::new();
-
-    // Use the default set of Interceptors
-    registry = register_default_interceptors(registry, &mut m)?;
-
-    // Create the API object with the MediaEngine
-    let api = APIBuilder::new()
-        .with_media_engine(m)
-        .with_interceptor_registry(registry)
-        .build();
-
-    // Prepare the configuration
-    let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-            ..Default::default()
-        }],
-        ..Default::default()
-    };
-
-    // Create a new RTCPeerConnection
-    let peer_connection = Arc::new(api.new_peer_connection(config).await?);
-    let audio_output_track = Arc::new(TrackLocalStaticSample::new(
-        RTCRtpCodecCapability {
-            mime_type: MIME_TYPE_OPUS.to_owned(),
+            RTPCodecType::Audio,
+        )?;
+
+        // Create a InterceptorRegistry. This is the user configurable RTP/RTCP Pipeline.
+        // This provides NACKs, RTCP Reports and other features. The API builds a fresh set
+        // of interceptors from it for each PeerConnection.
+        let mut registry = Registry::new();
+
+        // Use the default set of Interceptors
+        registry = register_default_interceptors(registry, &mut m)?;
+
+        // For firewalls that only let a few ports through
+        let mut setting_engine = SettingEngine::default();
+        if let Some([min, max]) = settings.udp_ports {
+            setting_engine.set_ephemeral_udp_port_range(min, max)?;
+        }
+
+        // Create the API object with the MediaEngine
+        let api = APIBuilder::new()
+            .with_media_engine(m)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(setting_engine)
+            .build();
+
+        Ok(Listeners {
+            api,
+            ice_servers: ice_servers(settings),
+            sessions: Arc::new(Sessions::default()),
+            slots: Arc::new(Semaphore::new(settings.max_listeners)),
+            max_listeners: settings.max_listeners,
+        })
+    }
+
+    // Sets up a new listener from their offer, and returns their id and our answer. Fails
+    // with `Full` when there are max_listeners already.
+    pub async fn add(&self, offer: RTCSessionDescription) -> Result<(String, RTCSessionDescription)> {
+        let slot = Arc::clone(&self.slots)
+            .try_acquire_owned()
+            .map_err(|_| Full(self.max_listeners))?;
+
+        // Prepare the configuration
+        let config = RTCConfiguration {
+            ice_servers: self.ice_servers.clone(),
             ..Default::default()
-        },
-        "track-audio".to_owned(),
-        "webrtc-rs".to_owned(),
-    ));
-
-    // Add this newly created track to the PeerConnection
-    let rtp_sender = peer_connection
-        .add_track(Arc::clone(&audio_output_track) as Arc<dyn TrackLocal + Send + Sync>)
-        .await?;
-
-    // Read incoming RTCP packets
-    // Before these packets are returned they are processed by interceptors. For things
-    // like NACK this needs to be called.
-    let m = "audio".to_owned();
-    tokio::spawn(async move {
-        let mut rtcp_buf = vec![0u8; 1500];
-        while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
-        println!("{} rtp_sender.read loop exit", m);
-        Result::<()>::Ok(())
-    });
+        };
 
-    // Wait for the offer to be pasted
-    let line = signalz::must_read_stdin()?;
-    let desc_data = signalz::decode(line.as_str())?;
-    let offer = serde_json::from_str::<RTCSessionDescription>(&desc_data)?;
-
-    // Set the remote SessionDescription
-    peer_connection.set_remote_description(offer).await?;
-
-    // Set a handler for when a new remote track starts, this handler copies inbound RTP packets,
-    // replaces the SSRC and sends them back
-    let _pc = Arc::downgrade(&peer_connection);
-
-    // Send a PLI on an interval so that the publisher is pushing a keyframe every rtcpPLIInterval
-    // This is a temporary fix until we implement incoming RTCP events, then we would push a PLI only when a viewer requests it
-    //let media_ssrc = track.ssrc();
-
-    let output_track = Arc::clone(&audio_output_track);
-    let output_track2 = Arc::clone(&output_track);
-
-    tokio::spawn(async move {
-        // Read RTP packets being sent to webrtc-rs
-        let mut ticker = tokio::time::interval(Duration::from_millis(20));
-        let mut encoder = audiopus::coder::Encoder::new(
-            audiopus::SampleRate::Hz48000,
-            audiopus::Channels::Stereo,
-            audiopus::Application::Audio,
-        )
-        .unwrap();
-        encoder.set_complexity(8).unwrap();
-        loop {
-            let taken = audio_buf_rx.recv().await.unwrap();
-            let mut in_buffer = [0i16; 960 * 2];
-            //let mut in_buffer = [0i16; 960];
-            for (i, sample) in taken.iter().enumerate() {
-                //in_buffer[i] = (*sample).0;
-                in_buffer[i * 2] = (*sample).0;
-                in_buffer[i * 2 + 1] = (*sample).1;
+        // Create a new RTCPeerConnection
+        let peer_connection = Arc::new(self.api.new_peer_connection(config).await?);
+        let id = format!("{:032x}", rand::random::<u128>());
+        let (answer, track) = match self.negotiate(&peer_connection, offer, id.clone()).await {
+            Ok(negotiated) => negotiated,
+            Err(err) => {
+                // Nothing else will ever close it
+                let _ = peer_connection.close().await;
+                return Err(err);
             }
-            let mut out_buffer = [0u8; 4096];
-            let size = encoder.encode(&in_buffer, &mut out_buffer).unwrap();
-            let data = Bytes::copy_from_slice(&out_buffer[0..size]);
-            if let Err(err) = output_track2
-                .write_sample(&Sample {
-                    data: data,
-                    duration: Duration::from_millis(20),
-                    ..Default::default()
-                })
-                .await
-            {
-                println!("output track write_rtp got error: {}", err);
+        };
+        let mut peers = self.sessions.peers.lock().unwrap();
+        peers.insert(id.clone(), Peer { connection: peer_connection, track, _slot: slot });
+        println!("Listener {} joined, {} listening", id, peers.len());
+        Ok((id, answer))
+    }
+
+    async fn negotiate(
+        &self,
+        peer_connection: &Arc<RTCPeerConnection>,
+        offer: RTCSessionDescription,
+        id: String,
+    ) -> Result<(RTCSessionDescription, Arc<TrackLocalStaticSample>)> {
+        let audio_output_track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                ..Default::default()
+            },
+            "track-audio".to_owned(),
+            "webrtc-rs".to_owned(),
+        ));
+
+        // Add this newly created track to the PeerConnection
+        let rtp_sender = peer_connection
+            .add_track(Arc::clone(&audio_output_track) as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+
+        // Read incoming RTCP packets
+        // Before these packets are returned they are processed by interceptors. For things
+        // like NACK this needs to be called.
+        tokio::spawn(async move {
+            let mut rtcp_buf = vec![0u8; 1500];
+            while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
+            Result::<()>::Ok(())
+        });
+
+        // Set the remote SessionDescription
+        peer_connection.set_remote_description(offer).await?;
+
+        // Weak, since the connection holds on to this handler and the sessions hold on to
+        // the connection
+        let sessions = Arc::downgrade(&self.sessions);
+        // Set the handler for Peer connection state
+        // This will notify you when the peer has connected/disconnected
+        peer_connection
+            .on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
+                println!("Listener {}: Peer Connection State has changed: {}", id, s);
+
+                // Disconnected can come back by itself, but failed and closed are for good.
+                // A peer that goes quiet fails after about 30 seconds with no network activity.
+                if s == RTCPeerConnectionState::Failed || s == RTCPeerConnectionState::Closed {
+                    let sessions = sessions.clone();
+                    let id = id.clone();
+                    // Closing from inside the handler would wait on the handler
+                    tokio::spawn(async move {
+                        if let Some(sessions) = sessions.upgrade() {
+                            sessions.remove(&id).await;
+                        }
+                    });
+                }
+
+                Box::pin(async {})
+            }))
+            .await;
+
+        // Create an answer
+        let answer = peer_connection.create_answer(None).await?;
+
+        // Create channel that is blocked until ICE Gathering is complete
+        let mut gather_complete = peer_connection.gathering_complete_promise().await;
+
+        // Sets the LocalDescription, and starts our UDP listeners
+        peer_connection.set_local_description(answer).await?;
+
+        // Block until ICE Gathering is complete, disabling trickle ICE
+        // we do this because we only can exchange one signaling message
+        // in a production application you should exchange ICE Candidates via OnICECandidate
+        let _ = gather_complete.recv().await;
+
+        let answer = peer_connection
+            .local_description()
+            .await
+            .ok_or_else(|| anyhow::Error::msg("generate local_description failed!"))?;
+        Ok((answer, audio_output_track))
+    }
+}
+
+// Encodes each frame once and sends it to every listener
+async fn send_frames(
+    mut audio_buf_rx: tokio::sync::mpsc::Receiver<Vec<(i16, i16)>>,
+    mut encoder: OpusEncoder,
+    audio_config: AudioConfig,
+    sessions: Arc<Sessions>,
+) {
+    while let Some(frame) = audio_buf_rx.recv().await {
+        let tracks = sessions.tracks();
+        if tracks.is_empty() {
+            continue;
+        }
+        let data = match encoder.encode(&frame) {
+            Ok(packet) => Bytes::copy_from_slice(packet),
+            Err(err) => {
+                println!("Couldn't encode audio for WebRTC: {}", err);
+                continue;
+            }
+        };
+        let sample = Sample {
+            data,
+            duration: audio_config.frame_duration,
+            ..Default::default()
+        };
+        for (id, track) in tracks {
+            // One listener's trouble is only theirs. Rather than wait the half a minute it
+            // takes their connection to fail, with an error every frame, hang up now.
+            if let Err(err) = track.write_sample(&sample).await {
+                println!("Couldn't send audio to listener {}, hanging up: {}", id, err);
+                let sessions = Arc::clone(&sessions);
+                tokio::spawn(async move { sessions.remove(&id).await });
+            }
+        }
+    }
+}
+
+// Answers offers pasted on stdin, one per line, for as long as we run
+async fn answer_stdin(listeners: Arc<Listeners>) {
+    let (lines_tx, mut lines) = tokio::sync::mpsc::channel::<String>(1);
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines().map_while(|line| line.ok()) {
+            if lines_tx.blocking_send(line.trim().to_owned()).is_err() {
                 break;
             }
-            let _ = ticker.tick().await;
         }
     });
+    println!("Paste a listener's offer to answer it, one per line");
+    while let Some(line) = lines.recv().await {
+        if line.is_empty() {
+            continue;
+        }
+        let answered = async {
+            let desc_data = signalz::decode(&line)?;
+            let offer = serde_json::from_str::<RTCSessionDescription>(&desc_data)?;
+            let (_, answer) = listeners.add(offer).await?;
+            Result::<String>::Ok(signalz::encode(&serde_json::to_string(&answer)?))
+        };
+        // Output the answer in base64 so we can paste it in browser
+        match answered.await {
+            Ok(b64) => println!("{}", b64),
+            Err(err) => println!("Couldn't answer that offer: {}", err),
+        }
+    }
+}
 
-    // Set the handler for Peer connection state
-    // This will notify you when the peer has connected/disconnected
-    peer_connection
-        .on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
-            println!("Peer Connection State has changed: {}", s);
-
-            if s == RTCPeerConnectionState::Failed {
-                // Wait until PeerConnection has had no network activity for 30 seconds or another failure. It may be reconnected using an ICE Restart.
-                // Use webrtc.PeerConnectionStateDisconnected if you are interested in detecting faster timeout.
-                // Note that the PeerConnection may come back from PeerConnectionStateDisconnected.
-                println!("Peer Connection has gone to failed exiting");
-                let _ = done_tx.try_send(());
+// Answers offers POSTed to /whep, with the answer in the response, for as long as we run
+async fn answer_http(listeners: Arc<Listeners>, mut signals: tokio::sync::mpsc::Receiver<Signal>) {
+    while let Some(signal) = signals.recv().await {
+        // Each on its own, since gathering candidates for one can take a while
+        let listeners = Arc::clone(&listeners);
+        tokio::spawn(async move {
+            match signal {
+                Signal::Offer { sdp, reply } => {
+                    let answered = async {
+                        let (id, answer) = listeners.add(RTCSessionDescription::offer(sdp)?).await?;
+                        Result::<(String, String)>::Ok((id, answer.sdp))
+                    };
+                    let answered = answered.await.map_err(|err| {
+                        println!("Couldn't answer an offer over HTTP: {}", err);
+                        if err.is::<Full>() {
+                            Refusal::Busy(err.to_string())
+                        } else {
+                            Refusal::BadOffer(err.to_string())
+                        }
+                    });
+                    // They may have given up waiting, and then the listener fails by itself
+                    let _ = reply.send(answered);
+                }
+                Signal::HangUp { id, reply } => {
+                    let removed = listeners.sessions.remove(&id).await;
+                    let _ = reply.send(removed);
+                }
             }
+        });
+    }
+}
 
-            Box::pin(async {})
-        }))
-        .await;
-
-    // Create an answer
-    let answer = peer_connection.create_answer(None).await?;
-
-    // Create channel that is blocked until ICE Gathering is complete
-    let mut gather_complete = peer_connection.gathering_complete_promise().await;
-
-    // Sets the LocalDescription, and starts our UDP listeners
-    peer_connection.set_local_description(answer).await?;
+// With none at all, only host candidates are gathered
+fn ice_servers(settings: &WebrtcSection) -> Vec<RTCIceServer> {
+    if settings.host_only {
+        return Vec::new();
+    }
+    let mut ice_servers = Vec::new();
+    if !settings.stun_servers.is_empty() {
+        ice_servers.push(RTCIceServer {
+            urls: settings.stun_servers.clone(),
+            ..Default::default()
+        });
+    }
+    for server in settings.ice_servers.iter() {
+        ice_servers.push(RTCIceServer {
+            urls: server.urls.clone(),
+            username: server.username.clone(),
+            credential: server.credential.clone(),
+            ..Default::default()
+        });
+    }
+    ice_servers
+}
 
-    // Block until ICE Gathering is complete, disabling trickle ICE
-    // we do this because we only can exchange one signaling message
-    // in a production application you should exchange ICE Candidates via OnICECandidate
-    let _ = gather_complete.recv().await;
+// The page at / that connects by itself, told which STUN servers to use. Only the ones
+// without credentials, since anyone can read the page, and our own relay candidates are
+// enough to get through a TURN server.
+fn listener_page(settings: &WebrtcSection) -> Result<String> {
+    let ice_servers = ice_servers(settings)
+        .into_iter()
+        .filter(|server| server.credential.is_empty())
+        .map(|server| serde_json::json!({ "urls": server.urls }))
+        .collect::<Vec<_>>();
+    Ok(include_str!("listener.html").replace("ICE_SERVERS", &serde_json::to_string(&ice_servers)?))
+}
 
-    // Output the answer in base64 so we can paste it in browser
-    if let Some(local_desc) = peer_connection.local_description().await {
-        let json_str = serde_json::to_string(&local_desc)?;
-        let b64 = signalz::encode(&json_str);
-        println!("{}", b64);
-    } else {
-        println!("generate local_description failed!");
+// Offers come over HTTP on `settings.listen` if it's set, or are pasted on stdin
+pub async fn webrtc_sink(
+    audio_buf_rx: tokio::sync::mpsc::Receiver<Vec<(i16, i16)>>,
+    audio_config: AudioConfig,
+    settings: WebrtcSection,
+) -> Result<(), anyhow::Error> {
+    let encoder = OpusEncoder::new(audio_config)?;
+    let listeners = Arc::new(Listeners::new(&settings)?);
+    tokio::spawn(send_frames(audio_buf_rx, encoder, audio_config, Arc::clone(&listeners.sessions)));
+    match settings.listen {
+        Some(address) => {
+            let signals = signalz::whep_server(address, listener_page(&settings)?)
+                .with_context(|| format!("Couldn't answer WebRTC listeners on {}", address))?;
+            println!("Listen at http://{}/", address);
+            tokio::spawn(answer_http(listeners, signals));
+        }
+        None => {
+            tokio::spawn(answer_stdin(listeners));
+        }
     }
-    //let timeout = tokio::time::sleep(Duration::from_secs(20));
-    //tokio::pin!(timeout);
-    // TODO: return cleanup closure.
-    // peer_connection.close().await?;
-    return Ok(());
+    Ok(())
 }